@@ -1,9 +1,21 @@
-use std::{fs::read_to_string, io, str::FromStr};
+use std::{
+    env,
+    fs::read_to_string,
+    io::{self, BufRead},
+    str::FromStr,
+};
 
-struct Error;
+#[derive(Debug)]
+enum Error {
+    MissingField,
+    ExtraField,
+    InvalidSection,
+    ReversedRange,
+}
 
+#[derive(Clone, Copy)]
 struct Elf(i32, i32);
-struct ElfPair(Elf, Elf);
+struct ElfGroup(Vec<Elf>);
 
 trait Contains<T> {
     fn contains(&self, other: &T) -> bool;
@@ -24,38 +36,545 @@ impl FromStr for Elf {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut parts = s.split('-');
-        let min = parts.next().ok_or(Error)?.parse().or(Err(Error))?;
-        let max = parts.next().ok_or(Error)?.parse().or(Err(Error))?;
+        let min = parse_section(parts.next())?;
+        let max = parse_section(parts.next())?;
+        if parts.next().is_some() {
+            return Err(Error::ExtraField);
+        }
+        Elf::new(min, max)
+    }
+}
+
+impl Elf {
+    fn new(min: i32, max: i32) -> Result<Self, Error> {
+        if min > max {
+            return Err(Error::ReversedRange);
+        }
         Ok(Elf(min, max))
     }
 }
 
-impl FromStr for ElfPair {
+fn parse_section(part: Option<&str>) -> Result<i32, Error> {
+    match part.map(str::trim) {
+        None | Some("") => Err(Error::MissingField),
+        Some(section) => section.parse().or(Err(Error::InvalidSection)),
+    }
+}
+
+impl FromStr for ElfGroup {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut elfs = s.split(',');
-        let elf1 = elfs.next().ok_or(Error)?.parse().or(Err(Error))?;
-        let elf2 = elfs.next().ok_or(Error)?.parse().or(Err(Error))?;
-        Ok(ElfPair(elf1, elf2))
+        let elfs = s
+            .split(',')
+            .map(|elf| match elf.trim() {
+                "" => Err(Error::MissingField),
+                elf => elf.parse(),
+            })
+            .collect::<Result<Vec<Elf>, _>>()?;
+        // A lone elf would trivially contain and overlap the rest of its group
+        if elfs.len() < 2 {
+            return Err(Error::MissingField);
+        }
+        Ok(ElfGroup(elfs))
+    }
+}
+
+impl ElfGroup {
+    fn any_contains_all(&self) -> bool {
+        self.0.iter().enumerate().any(|(i, elf1)| {
+            self.0
+                .iter()
+                .enumerate()
+                .all(|(j, elf2)| i == j || elf1.contains(elf2))
+        })
+    }
+
+    fn all_overlap(&self) -> bool {
+        self.pairs().all(|(elf1, elf2)| elf1.overlaps(elf2))
+    }
+
+    fn pairwise_overlaps(&self) -> usize {
+        self.pairs()
+            .filter(|(elf1, elf2)| elf1.overlaps(elf2))
+            .count()
+    }
+
+    fn pairs(&self) -> impl Iterator<Item = (&Elf, &Elf)> {
+        self.0
+            .iter()
+            .enumerate()
+            .flat_map(|(i, elf1)| self.0[i + 1..].iter().map(move |elf2| (elf1, elf2)))
+    }
+}
+
+fn parse_groups(contents: &str) -> io::Result<Vec<ElfGroup>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            line.parse().map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {err:?} in {line:?}", idx + 1),
+                )
+            })
+        })
+        .collect()
+}
+
+// Positions are i64 so that a range ending at i32::MAX can still close
+struct Coverage {
+    covered: u64,
+    gaps: Vec<(i64, i64)>,
+    most_overlapped: (i64, usize),
+    crowded: Vec<(i64, i64)>,
+}
+
+// Sweep-line over every range: each elf opens at its first section and
+// closes right after its last one, so between two consecutive event
+// positions the number of elves covering a section stays constant. Ranges
+// are never reversed, so every elf closes after it opened.
+fn coverage(elfs: &[Elf], threshold: usize) -> Option<Coverage> {
+    let mut events: Vec<(i64, bool)> = elfs
+        .iter()
+        .flat_map(|&Elf(min, max)| [(i64::from(min), true), (i64::from(max) + 1, false)])
+        .collect();
+    events.sort_unstable();
+
+    let mut result = Coverage {
+        covered: 0,
+        gaps: Vec::new(),
+        most_overlapped: (events.first()?.0, 0),
+        crowded: Vec::new(),
+    };
+
+    let mut depth: usize = 0;
+    let mut prev = events[0].0;
+    for (pos, opens) in events {
+        if pos > prev {
+            let (start, end) = (prev, pos - 1);
+            if depth == 0 {
+                result.gaps.push((start, end));
+            } else {
+                result.covered += (pos - prev) as u64;
+            }
+            if depth > result.most_overlapped.1 {
+                result.most_overlapped = (start, depth);
+            }
+            if depth > threshold {
+                match result.crowded.last_mut() {
+                    Some(last) if last.1 + 1 == start => last.1 = end,
+                    _ => result.crowded.push((start, end)),
+                }
+            }
+            prev = pos;
+        }
+        if opens {
+            depth += 1;
+        } else {
+            depth -= 1;
+        }
+    }
+
+    Some(result)
+}
+
+fn format_ranges(ranges: &[(i64, i64)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+struct Assignment {
+    line: usize,
+    elf: Elf,
+}
+
+// Assignments sorted by their first section, viewed as an implicit balanced
+// tree (the middle of every slice is its root) where each node also records
+// the furthest last section found in its subtree. Subtrees that end before
+// the queried range can then be skipped entirely.
+struct IntervalIndex {
+    assignments: Vec<Assignment>,
+    max_end: Vec<i32>,
+}
+
+impl IntervalIndex {
+    fn new(groups: &[ElfGroup]) -> Self {
+        let mut assignments: Vec<Assignment> = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(line, ElfGroup(elfs))| {
+                elfs.iter().map(move |&elf| Assignment {
+                    line: line + 1,
+                    elf,
+                })
+            })
+            .collect();
+        assignments.sort_unstable_by_key(|Assignment { elf, .. }| (elf.0, elf.1));
+
+        let mut index = IntervalIndex {
+            max_end: vec![i32::MIN; assignments.len()],
+            assignments,
+        };
+        index.build(0, index.assignments.len());
+        index
+    }
+
+    fn build(&mut self, lo: usize, hi: usize) -> i32 {
+        if lo >= hi {
+            return i32::MIN;
+        }
+        let mid = (lo + hi) / 2;
+        let max_end = self
+            .build(lo, mid)
+            .max(self.build(mid + 1, hi))
+            .max(self.assignments[mid].elf.1);
+        self.max_end[mid] = max_end;
+        max_end
+    }
+
+    fn containing(&self, section: i32) -> Vec<&Assignment> {
+        self.overlapping(&Elf(section, section))
+    }
+
+    // The range comes from `Elf::new`, so it is never reversed
+    fn overlapping(&self, range: &Elf) -> Vec<&Assignment> {
+        let mut found = Vec::new();
+        self.collect(0, self.assignments.len(), range, &mut found);
+        found
+    }
+
+    fn collect<'a>(&'a self, lo: usize, hi: usize, range: &Elf, found: &mut Vec<&'a Assignment>) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        if self.max_end[mid] < range.0 {
+            return;
+        }
+        self.collect(lo, mid, range, found);
+        let assignment = &self.assignments[mid];
+        // Everything from here on starts after the queried range
+        if assignment.elf.0 > range.1 {
+            return;
+        }
+        if assignment.elf.overlaps(range) {
+            found.push(assignment);
+        }
+        self.collect(mid + 1, hi, range, found);
+    }
+
+    // Pairs of assignments (from any line) where one contains the other.
+    // With assignments ordered by start and then by descending end, every
+    // container comes before what it contains, so a Fenwick tree over the
+    // ends seen so far counts the containers of each assignment.
+    fn containing_pairs(&self) -> usize {
+        let mut order: Vec<&Elf> = self.assignments.iter().map(|a| &a.elf).collect();
+        order.sort_unstable_by_key(|elf| (elf.0, std::cmp::Reverse(elf.1)));
+
+        let mut ends: Vec<i32> = order.iter().map(|elf| elf.1).collect();
+        ends.sort_unstable();
+        ends.dedup();
+
+        let mut tree = vec![0usize; ends.len() + 1];
+        let mut pairs = 0;
+        for (seen, elf) in order.iter().enumerate() {
+            let rank = ends.partition_point(|&end| end < elf.1);
+            let mut ended_before = 0;
+            let mut i = rank;
+            while i > 0 {
+                ended_before += tree[i];
+                i &= i - 1;
+            }
+            pairs += seen - ended_before;
+
+            let mut i = rank + 1;
+            while i < tree.len() {
+                tree[i] += 1;
+                i += i & i.wrapping_neg();
+            }
+        }
+        pairs
+    }
+}
+
+fn print_assignments(assignments: &[&Assignment]) {
+    println!("{}", assignments.len());
+    for Assignment { line, elf } in assignments {
+        println!("line {line}: {}-{}", elf.0, elf.1);
+    }
+}
+
+// Answers one query per line of `input`, all from the same index, so the
+// cost of building it is paid once per run rather than once per query.
+// Lines are `contains <section>`, `overlaps <start> <end>` or
+// `containing-pairs`; blank lines are ignored.
+fn answer_queries(index: &IntervalIndex, input: impl BufRead) -> io::Result<()> {
+    for (idx, line) in input.lines().enumerate() {
+        let line = line?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Query {}: invalid query {line:?}", idx + 1),
+            )
+        };
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [] => {}
+            ["contains", section] => {
+                let section = section.parse().map_err(|_| invalid())?;
+                print_assignments(&index.containing(section));
+            }
+            ["overlaps", min, max] => {
+                let min = min.parse().map_err(|_| invalid())?;
+                let max = max.parse().map_err(|_| invalid())?;
+                let range = Elf::new(min, max).map_err(|_| invalid())?;
+                print_assignments(&index.overlapping(&range));
+            }
+            ["containing-pairs"] => println!("{}", index.containing_pairs()),
+            _ => return Err(invalid()),
+        }
     }
+    Ok(())
+}
+
+fn parse_arg<T: FromStr>(args: &[String], idx: usize, name: &str) -> io::Result<T> {
+    args.get(idx)
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {name}")))
 }
 
 fn main() -> io::Result<()> {
     let contents = read_to_string("input.txt")?;
-    let part1: usize = contents
-        .lines()
-        .filter_map(|line| line.parse().ok())
-        .filter(|ElfPair(elf1, elf2)| elf1.contains(elf2) || elf2.contains(elf1))
-        .count();
+    let groups = parse_groups(&contents)?;
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let part2: usize = contents
-        .lines()
-        .filter_map(|line| line.parse().ok())
-        .filter(|ElfPair(elf1, elf2)| elf1.overlaps(elf2) || elf2.overlaps(elf1))
+    if args.first().map(String::as_str) == Some("coverage") {
+        let threshold: usize = match args.get(1) {
+            Some(_) => parse_arg(&args, 1, "coverage threshold")?,
+            None => 1,
+        };
+        let elfs: Vec<Elf> = groups.into_iter().flat_map(|ElfGroup(elfs)| elfs).collect();
+        let coverage = coverage(&elfs, threshold)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No assignments found"))?;
+        let (section, multiplicity) = coverage.most_overlapped;
+
+        println!("covered sections: {}", coverage.covered);
+        println!("gaps: {}", format_ranges(&coverage.gaps));
+        println!("most overlapped: section {section} ({multiplicity} elves)");
+        println!(
+            "covered by more than {threshold} elves: {}",
+            format_ranges(&coverage.crowded)
+        );
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some("pairwise") {
+        let overlaps: usize = groups.iter().map(ElfGroup::pairwise_overlaps).sum();
+        println!("{overlaps}");
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some("contains") {
+        let section = parse_arg(&args, 1, "section")?;
+        print_assignments(&IntervalIndex::new(&groups).containing(section));
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some("overlaps") {
+        let min = parse_arg(&args, 1, "range start")?;
+        let max = parse_arg(&args, 2, "range end")?;
+        let range = Elf::new(min, max).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{err:?} {min}-{max}"))
+        })?;
+        print_assignments(&IntervalIndex::new(&groups).overlapping(&range));
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some("queries") {
+        return answer_queries(&IntervalIndex::new(&groups), io::stdin().lock());
+    }
+
+    if args.first().map(String::as_str) == Some("containing-pairs") {
+        println!("{}", IntervalIndex::new(&groups).containing_pairs());
+        return Ok(());
+    }
+
+    let part1 = groups
+        .iter()
+        .filter(|group| group.any_contains_all())
         .count();
+    let part2 = groups.iter().filter(|group| group.all_overlap()).count();
 
     println!("{part1}");
     println!("{part2}");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small ranges from a fixed-seed xorshift, so that they overlap a lot
+    fn random_elfs(count: usize, mut seed: u64) -> Vec<Elf> {
+        let mut next = move |bound: i32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as i32
+        };
+        (0..count)
+            .map(|_| {
+                let min = 1 + next(40);
+                Elf(min, min + next(10))
+            })
+            .collect()
+    }
+
+    fn depth_at(elfs: &[Elf], section: i32) -> usize {
+        elfs.iter()
+            .filter(|elf| elf.contains(&Elf(section, section)))
+            .count()
+    }
+
+    // Maximal runs of sections between the first and last covered ones
+    fn runs(elfs: &[Elf], keep: impl Fn(usize) -> bool) -> Vec<(i64, i64)> {
+        let first = elfs.iter().map(|elf| elf.0).min().unwrap();
+        let last = elfs.iter().map(|elf| elf.1).max().unwrap();
+        let mut runs: Vec<(i64, i64)> = Vec::new();
+        for section in first..=last {
+            if !keep(depth_at(elfs, section)) {
+                continue;
+            }
+            let section = i64::from(section);
+            match runs.last_mut() {
+                Some(run) if run.1 + 1 == section => run.1 = section,
+                _ => runs.push((section, section)),
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn coverage_matches_counting_every_section() {
+        for seed in 1..50 {
+            let elfs = random_elfs(1 + seed as usize % 12, seed);
+            for threshold in 0..4 {
+                let result = coverage(&elfs, threshold).unwrap();
+                let covered: u64 = runs(&elfs, |depth| depth > 0)
+                    .iter()
+                    .map(|(start, end)| (end - start + 1) as u64)
+                    .sum();
+                assert_eq!(result.covered, covered);
+                assert_eq!(result.gaps, runs(&elfs, |depth| depth == 0));
+                assert_eq!(result.crowded, runs(&elfs, |depth| depth > threshold));
+
+                let first = elfs.iter().map(|elf| elf.0).min().unwrap();
+                let last = elfs.iter().map(|elf| elf.1).max().unwrap();
+                let most = (first..=last).map(|s| depth_at(&elfs, s)).max().unwrap();
+                let section = (first..=last)
+                    .find(|&s| depth_at(&elfs, s) == most)
+                    .unwrap();
+                assert_eq!(result.most_overlapped, (i64::from(section), most));
+            }
+        }
+    }
+
+    #[test]
+    fn coverage_reaches_the_end_of_the_sections() {
+        let result = coverage(&[Elf(1, i32::MAX), Elf(i32::MAX, i32::MAX)], 1).unwrap();
+        assert_eq!(result.covered, i32::MAX as u64);
+        assert_eq!(result.most_overlapped, (i64::from(i32::MAX), 2));
+        assert!(coverage(&[], 1).is_none());
+    }
+
+    #[test]
+    fn group_parse_errors() {
+        let parse = |line: &str| line.parse::<ElfGroup>().err();
+        assert!(matches!(parse("1-2,"), Some(Error::MissingField)));
+        assert!(matches!(parse("1-2"), Some(Error::MissingField)));
+        assert!(matches!(parse("1-,3-4"), Some(Error::MissingField)));
+        assert!(matches!(parse("1-2-3,4-5"), Some(Error::ExtraField)));
+        assert!(matches!(parse("a-b,1-2"), Some(Error::InvalidSection)));
+        assert!(matches!(parse("5-3,1-2"), Some(Error::ReversedRange)));
+        assert!(parse("2-4,6-8").is_none());
+        assert!(parse(" 2-4 , 6-8 ,1-1").is_none());
+    }
+
+    #[test]
+    fn group_checks() {
+        let group = |line: &str| line.parse::<ElfGroup>().ok().unwrap();
+        assert!(group("2-8,3-7").any_contains_all());
+        assert!(!group("2-8,3-7,1-1").any_contains_all());
+        assert!(group("5-7,7-9").all_overlap());
+        assert!(!group("5-7,7-9,1-4").all_overlap());
+        assert_eq!(group("5-7,7-9,1-4").pairwise_overlaps(), 1);
+    }
+
+    #[test]
+    fn parse_groups_reports_the_line() {
+        let err = parse_groups("2-4,6-8\n5-3,1-2\n").err().unwrap();
+        assert!(err.to_string().starts_with("Line 2: ReversedRange"));
+    }
+
+    fn random_groups(lines: usize, seed: u64) -> Vec<ElfGroup> {
+        random_elfs(lines * 2, seed)
+            .chunks(2)
+            .map(|elfs| ElfGroup(elfs.to_vec()))
+            .collect()
+    }
+
+    fn sorted(assignments: Vec<&Assignment>) -> Vec<(usize, i32, i32)> {
+        let mut found: Vec<_> = assignments
+            .iter()
+            .map(|a| (a.line, a.elf.0, a.elf.1))
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    #[test]
+    fn index_matches_scanning_every_assignment() {
+        for seed in 1..30 {
+            let groups = random_groups(seed as usize % 20, seed);
+            let index = IntervalIndex::new(&groups);
+            let all: Vec<Assignment> = groups
+                .iter()
+                .enumerate()
+                .flat_map(|(line, ElfGroup(elfs))| {
+                    elfs.iter().map(move |&elf| Assignment {
+                        line: line + 1,
+                        elf,
+                    })
+                })
+                .collect();
+            let scan = |range: &Elf| sorted(all.iter().filter(|a| a.elf.overlaps(range)).collect());
+
+            for min in 0..55 {
+                assert_eq!(sorted(index.containing(min)), scan(&Elf(min, min)));
+                for max in min..min + 8 {
+                    let range = Elf::new(min, max).ok().unwrap();
+                    assert_eq!(sorted(index.overlapping(&range)), scan(&range));
+                }
+            }
+
+            let pairs = (0..all.len())
+                .flat_map(|i| (i + 1..all.len()).map(move |j| (i, j)))
+                .filter(|&(i, j)| {
+                    all[i].elf.contains(&all[j].elf) || all[j].elf.contains(&all[i].elf)
+                })
+                .count();
+            assert_eq!(index.containing_pairs(), pairs);
+        }
+    }
+
+    #[test]
+    fn reversed_query_ranges_are_rejected() {
+        assert!(matches!(Elf::new(5, 2), Err(Error::ReversedRange)));
+        assert!(Elf::new(2, 2).is_ok());
+    }
+}
@@ -1,116 +1,706 @@
-use std::{collections::HashMap, fs::read_to_string, io, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fmt,
+    fs::read_to_string,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
 
 struct CrateError;
-struct StackError;
 struct MoveError;
-struct ProgramError;
 
-#[derive(Clone)]
-struct Crate(char);
+// Positions are 1-based line and column numbers within the drawing
+#[derive(Debug)]
+enum StackError {
+    MissingFooter,
+    InvalidFooter(String),
+    DuplicateStack(i32),
+    MalformedCrate { line: usize, column: usize },
+    UnalignedCrate { line: usize, column: usize },
+    FloatingCrate { line: usize, stack: i32 },
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::MissingFooter => write!(f, "the drawing has no stack-number footer"),
+            StackError::InvalidFooter(token) => write!(f, "invalid stack number {token:?}"),
+            StackError::DuplicateStack(id) => write!(f, "stack {id} is numbered twice"),
+            StackError::MalformedCrate { line, column } => {
+                write!(f, "malformed crate at line {line}, column {column}")
+            }
+            StackError::UnalignedCrate { line, column } => write!(
+                f,
+                "crate at line {line}, column {column} does not sit above a single stack number"
+            ),
+            StackError::FloatingCrate { line, stack } => {
+                write!(
+                    f,
+                    "crate at line {line} floats above a gap in stack {stack}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ProgramError {
+    MissingMoves,
+    Stack(StackError),
+    Moves,
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::MissingMoves => write!(f, "no blank line before the moves"),
+            ProgramError::Stack(err) => write!(f, "{err}"),
+            ProgramError::Moves => write!(f, "invalid moves"),
+        }
+    }
+}
+struct CraneError;
+struct PolicyError;
+
+#[derive(Debug)]
+enum InvalidMove {
+    TooFewCrates {
+        index: usize,
+        from: i32,
+        to: i32,
+        requested: usize,
+        available: usize,
+    },
+    UnknownStack {
+        index: usize,
+        move_: Move,
+        stack: i32,
+    },
+}
+
+impl fmt::Display for InvalidMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidMove::TooFewCrates {
+                index,
+                from,
+                to,
+                requested,
+                available,
+            } => write!(
+                f,
+                "move #{} (move {requested} from {from} to {to}): stack {from} only has {available} crate(s)",
+                index + 1,
+            ),
+            InvalidMove::UnknownStack {
+                index,
+                move_,
+                stack,
+            } => write!(
+                f,
+                "move #{} (move {} from {} to {}): there is no stack {stack}",
+                index + 1,
+                move_.move_,
+                move_.from,
+                move_.to,
+            ),
+        }
+    }
+}
+
+// What to do with a move that asks for more crates than its source holds, or
+// that names a stack missing from the drawing
+#[derive(Clone, Copy)]
+enum MovePolicy {
+    Abort,
+    Skip,
+    Partial,
+}
+
+impl FromStr for MovePolicy {
+    type Err = PolicyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(MovePolicy::Abort),
+            "skip" => Ok(MovePolicy::Skip),
+            "partial" => Ok(MovePolicy::Partial),
+            _ => Err(PolicyError),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct Crate(String);
 
 impl FromStr for Crate {
     type Err = CrateError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if !s.trim().starts_with('[') {
-            return Err(CrateError);
-        }
-        s.chars()
-            .nth(1)
-            .filter(|&c| c != ' ')
-            .map(Crate)
+        s.trim()
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .filter(|label| !label.is_empty() && !label.contains(['[', ']', ' ']))
+            .map(|label| Crate(label.to_string()))
             .ok_or(CrateError)
     }
 }
 
 #[derive(Clone)]
 struct Stack {
-    stacks: HashMap<i32, Vec<Crate>>,
+    stacks: BTreeMap<i32, Vec<Crate>>,
+    history: History,
+}
+
+// Primitive changes made to the stacks, recorded with the crates involved so
+// they can be reverted or replayed without knowing which crane made them
+#[derive(Clone)]
+enum Operation {
+    Take { from: i32, crates: Vec<Crate> },
+    Put { to: i32, crates: Vec<Crate> },
+}
+
+// One entry per applied move, skipped moves included, so that the number of
+// entries in `done` is always the index of the next move
+#[derive(Clone, Default)]
+struct History {
+    done: Vec<Vec<Operation>>,
+    undone: Vec<Vec<Operation>>,
+    pending: Vec<Operation>,
 }
 
+// Whitespace separated tokens of a line with their [start, end) char columns
+fn tokens(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let chars = line
+        .char_indices()
+        .chain(std::iter::once((line.len(), ' ')));
+    for (column, (offset, c)) in chars.enumerate() {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some((column, offset)),
+            (Some((start_column, start_offset)), true) => {
+                tokens.push((start_column, column, &line[start_offset..offset]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+// The footer line numbers the stacks, and every crate belongs to the one
+// stack number its label sits above, whatever the spacing between them.
 impl FromStr for Stack {
     type Err = StackError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let stacks = s
-            .lines()
-            .take_while(|line| line.contains('['))
-            .collect::<Vec<_>>()
+        let lines: Vec<&str> = s.lines().filter(|line| !line.trim().is_empty()).collect();
+        let (footer, rows) = lines.split_last().ok_or(StackError::MissingFooter)?;
+
+        let mut columns = Vec::new();
+        let mut stacks = BTreeMap::new();
+        for (start, end, token) in tokens(footer) {
+            let id: i32 = token
+                .parse()
+                .map_err(|_| StackError::InvalidFooter(token.to_string()))?;
+            if stacks.insert(id, Vec::new()).is_some() {
+                return Err(StackError::DuplicateStack(id));
+            }
+            columns.push((start, end, id));
+        }
+        if columns.is_empty() {
+            return Err(StackError::MissingFooter);
+        }
+
+        for (row, line) in rows.iter().enumerate().rev() {
+            let height = rows.len() - 1 - row;
+            for (start, end, token) in tokens(line) {
+                let (line, column) = (row + 1, start + 1);
+                let crate_ = token
+                    .parse::<Crate>()
+                    .map_err(|_| StackError::MalformedCrate { line, column })?;
+                let below = columns
+                    .iter()
+                    .filter(|(col_start, col_end, _)| *col_start < end && start < *col_end)
+                    .map(|(_, _, id)| *id)
+                    .collect::<Vec<_>>();
+                let [id] = below[..] else {
+                    return Err(StackError::UnalignedCrate { line, column });
+                };
+                let stack = stacks.entry(id).or_default();
+                if stack.len() != height {
+                    return Err(StackError::FloatingCrate { line, stack: id });
+                }
+                stack.push(crate_);
+            }
+        }
+
+        Ok(Stack {
+            stacks,
+            history: History::default(),
+        })
+    }
+}
+
+// Renders the same drawing `from_str` parses: one row per crate height,
+// with every stack padded to the width of its longest label or number (three
+// columns for single characters), followed by the stack-number footer.
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.draw(None))
+    }
+}
+
+impl Stack {
+    // Draws the stacks, optionally wrapping the top `count` crates of one
+    // stack in the highlight colour
+    fn draw(&self, highlight: Option<(i32, usize)>) -> String {
+        let widths: Vec<usize> = self
+            .stacks
             .iter()
+            .map(|(id, stack)| {
+                let label = stack.iter().map(|c| c.0.chars().count()).max();
+                (label.unwrap_or(1) + 2).max(id.to_string().len() + 2)
+            })
+            .collect();
+        let height = self.stacks.values().map(Vec::len).max().unwrap_or(0);
+        let mut lines = (0..height)
             .rev()
-            .flat_map(|line| {
-                line.as_bytes()
-                    .chunks(4)
-                    .enumerate()
-                    .filter_map(|(idx, chunk)| {
-                        (chunk[0] == b'[' && chunk[1] != b' ')
-                            .then_some((idx as i32 + 1, Crate(chunk[1] as char)))
+            .map(|row| {
+                self.stacks
+                    .iter()
+                    .zip(&widths)
+                    .map(|((&id, stack), &width)| match stack.get(row) {
+                        Some(crate_) => {
+                            let cell = format!("{:^width$}", format!("[{}]", crate_.0));
+                            match highlight {
+                                Some((lifted, count))
+                                    if lifted == id && row + count >= stack.len() =>
+                                {
+                                    format!("{HIGHLIGHT}{cell}{RESET}")
+                                }
+                                _ => cell,
+                            }
+                        }
+                        None => " ".repeat(width),
                     })
+                    .collect::<Vec<_>>()
+                    .join(" ")
             })
-            .fold(
-                HashMap::<i32, Vec<Crate>>::new(),
-                |mut acc, (pos, crate_)| {
-                    acc.entry(pos).or_default().push(crate_);
-                    acc
-                },
-            );
+            .collect::<Vec<_>>();
+        lines.push(
+            self.stacks
+                .keys()
+                .zip(&widths)
+                .map(|(id, &width)| format!("{id:^width$}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        lines.join("\n")
+    }
 
-        Ok(Stack { stacks })
+    fn len(&self, id: i32) -> usize {
+        self.stacks.get(&id).map_or(0, Vec::len)
     }
-}
 
-impl Stack {
     fn top_crates(&self) -> Result<String, StackError> {
-        let max_key = self.stacks.keys().max().unwrap_or(&0);
-        let top = (1..=*max_key)
-            .filter_map(|i| {
-                self.stacks
-                    .get(&i)
-                    .and_then(|stack| stack.last().map(|crate_| crate_.0))
-                    .filter(|&c| c != ' ')
-            })
+        let top = self
+            .stacks
+            .values()
+            .filter_map(|stack| stack.last().map(|crate_| crate_.0.as_str()))
             .collect();
         Ok(top)
     }
 
-    fn apply_moves(&mut self, moves: &[Move]) {
-        for move_ in moves {
-            for _ in 0..move_.move_ {
-                let crate_to_move = self
-                    .stacks
-                    .get_mut(&move_.from)
-                    .and_then(|stack| stack.pop());
+    // Applies every move in order. With `Abort` the first invalid move is
+    // returned as an error and the stack is left as it was right before it;
+    // otherwise the invalid moves are returned once all moves are done.
+    fn apply_moves(
+        &mut self,
+        crane: &dyn Crane,
+        moves: &[Move],
+        policy: MovePolicy,
+    ) -> Result<Vec<InvalidMove>, InvalidMove> {
+        let mut invalid = Vec::new();
+        for (idx, move_) in moves.iter().enumerate() {
+            invalid.extend(self.apply_move(crane, idx, move_, policy)?);
+        }
+        Ok(invalid)
+    }
 
-                if let Some(crate_) = crate_to_move {
-                    self.stacks.entry(move_.to).or_default().push(crate_);
-                }
+    // Validates the move before handing it to the crane. Moves asking for
+    // more crates than their source holds are either skipped or carried out
+    // with the crates available, depending on the policy, and reported back.
+    // Moves between stacks that don't exist are never carried out.
+    fn apply_move(
+        &mut self,
+        crane: &dyn Crane,
+        idx: usize,
+        move_: &Move,
+        policy: MovePolicy,
+    ) -> Result<Option<InvalidMove>, InvalidMove> {
+        if let Some(&stack) = [move_.from, move_.to]
+            .iter()
+            .find(|id| !self.stacks.contains_key(id))
+        {
+            let error = InvalidMove::UnknownStack {
+                index: idx,
+                move_: move_.clone(),
+                stack,
+            };
+            if let MovePolicy::Abort = policy {
+                return Err(error);
             }
+            self.commit();
+            return Ok(Some(error));
+        }
+
+        let requested = move_.move_;
+        let available = self.len(move_.from);
+        if available >= requested {
+            crane.apply(self, idx, move_);
+            self.commit();
+            return Ok(None);
         }
+
+        let error = InvalidMove::TooFewCrates {
+            index: idx,
+            from: move_.from,
+            to: move_.to,
+            requested,
+            available,
+        };
+        match policy {
+            MovePolicy::Abort => return Err(error),
+            MovePolicy::Skip => {}
+            MovePolicy::Partial => crane.apply(
+                self,
+                idx,
+                &Move {
+                    move_: available,
+                    ..move_.clone()
+                },
+            ),
+        }
+        self.commit();
+        Ok(Some(error))
     }
 
-    fn apply_moves_part2(&mut self, moves: &[Move]) {
-        for move_ in moves {
-            let from_stack = self.stacks.get_mut(&move_.from);
+    // Only called with stacks `apply_move` checked to exist
+    fn take(&mut self, from: i32, count: usize) -> Vec<Crate> {
+        let from_vec = self
+            .stacks
+            .get_mut(&from)
+            .expect("moves only take from existing stacks");
+        let crates = from_vec.split_off(from_vec.len().saturating_sub(count));
+        self.history.pending.push(Operation::Take {
+            from,
+            crates: crates.clone(),
+        });
+        crates
+    }
+
+    fn put(&mut self, to: i32, crates: impl IntoIterator<Item = Crate>) {
+        let crates: Vec<Crate> = crates.into_iter().collect();
+        self.stacks
+            .get_mut(&to)
+            .expect("moves only put onto existing stacks")
+            .extend(crates.iter().cloned());
+        self.history.pending.push(Operation::Put { to, crates });
+    }
 
-            let crates_to_move = if let Some(from_vec) = from_stack {
-                if from_vec.len() < move_.move_ as usize {
-                    continue;
+    // Closes the operations made by the current move into a history entry.
+    // A new move makes the undone ones unreachable.
+    fn commit(&mut self) {
+        let operations = std::mem::take(&mut self.history.pending);
+        self.history.done.push(operations);
+        self.history.undone.clear();
+    }
+
+    fn undo(&mut self) -> bool {
+        let Some(operations) = self.history.done.pop() else {
+            return false;
+        };
+        for operation in operations.iter().rev() {
+            match operation {
+                Operation::Take { from, crates } => {
+                    self.stacks
+                        .entry(*from)
+                        .or_default()
+                        .extend(crates.iter().cloned());
                 }
-                let split_at = from_vec.len() - move_.move_ as usize;
-                from_vec.split_off(split_at)
-            } else {
+                Operation::Put { to, crates } => {
+                    let to_vec = self.stacks.entry(*to).or_default();
+                    to_vec.truncate(to_vec.len() - crates.len());
+                }
+            }
+        }
+        self.history.undone.push(operations);
+        true
+    }
+
+    fn redo(&mut self) -> bool {
+        let Some(operations) = self.history.undone.pop() else {
+            return false;
+        };
+        for operation in &operations {
+            match operation {
+                Operation::Take { from, crates } => {
+                    let from_vec = self.stacks.entry(*from).or_default();
+                    from_vec.truncate(from_vec.len() - crates.len());
+                }
+                Operation::Put { to, crates } => {
+                    self.stacks
+                        .entry(*to)
+                        .or_default()
+                        .extend(crates.iter().cloned());
+                }
+            }
+        }
+        self.history.done.push(operations);
+        true
+    }
+
+    // Number of moves applied so far, i.e. the index of the next move
+    fn position(&self) -> usize {
+        self.history.done.len()
+    }
+
+    // Moves back and forth through the history until exactly `moves` moves
+    // have been applied. Fails if that is beyond what has been recorded.
+    fn seek(&mut self, moves: usize) -> bool {
+        if moves > self.position() + self.history.undone.len() {
+            return false;
+        }
+        while self.position() > moves {
+            self.undo();
+        }
+        while self.position() < moves {
+            self.redo();
+        }
+        true
+    }
+
+    // The state right after the first `moves` recorded moves
+    fn state_at(&self, moves: usize) -> Option<Stack> {
+        let mut stack = self.clone();
+        stack.seek(moves).then_some(stack)
+    }
+
+    // Compact identity of the crates' arrangement after `moves` moves, for the
+    // solver to recognise states it has already reached
+    fn state_key(&self, moves: usize) -> String {
+        let mut key = (moves % 2).to_string();
+        for stack in self.stacks.values() {
+            key.push('\n');
+            for crate_ in stack {
+                key.push_str(&crate_.0);
+                key.push(' ');
+            }
+        }
+        key
+    }
+
+    fn same_crates(&self, other: &Stack) -> bool {
+        self.stacks.keys().chain(other.stacks.keys()).all(|i| {
+            self.stacks.get(i).map_or(&[][..], Vec::as_slice)
+                == other.stacks.get(i).map_or(&[][..], Vec::as_slice)
+        })
+    }
+}
+
+// Replays the recorded histories of two runs over the same moves side by
+// side for the first move after which their stacks differ. Runs can line up
+// again after diverging, so every state is compared in order.
+fn first_divergence(a: &Stack, b: &Stack) -> Option<usize> {
+    let moves = a.position().min(b.position());
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.seek(0);
+    b.seek(0);
+    (1..=moves).find(|_| {
+        a.redo();
+        b.redo();
+        !a.same_crates(&b)
+    })
+}
+
+// Lower bound on the moves left to reach the target top row: a move only
+// changes the top crate of its source and destination stacks. Only usable
+// when the target names a top crate for every stack.
+fn misplaced_tops(stack: &Stack, target: &[char]) -> usize {
+    if target.len() != stack.stacks.len() {
+        return 0;
+    }
+    let misplaced = stack
+        .stacks
+        .values()
+        .zip(target)
+        .filter(|(stack, &c)| stack.last().is_none_or(|crate_| !crate_.0.chars().eq([c])))
+        .count();
+    misplaced.div_ceil(2)
+}
+
+// Outcome of looking for moves that reach a target top row
+enum Search {
+    Found(Vec<Move>),
+    // Every state within the depth was tried
+    Exhausted,
+    // Stopped after reaching this many distinct states
+    GaveUp(usize),
+}
+
+// Breadth-first search over every possible move, skipping states that were
+// already reached by as few moves, so the first solution found is a shortest
+// one. The alternating crane behaves differently on odd and even moves, so
+// states are also told apart by the parity of the moves leading to them.
+fn solve(
+    stack: &Stack,
+    crane: &dyn Crane,
+    target: &str,
+    max_depth: usize,
+    max_states: usize,
+) -> Search {
+    let target: Vec<char> = target.chars().collect();
+    let start = Stack {
+        stacks: stack.stacks.clone(),
+        history: History::default(),
+    };
+    let mut visited = HashSet::from([start.state_key(0)]);
+    // Every reached state's parent and the move that led to it
+    let mut parents: Vec<(usize, Option<Move>)> = vec![(0, None)];
+    let mut frontier = vec![(0, start)];
+    for depth in 0..=max_depth {
+        let mut next = Vec::new();
+        for (node, state) in frontier {
+            if state
+                .top_crates()
+                .is_ok_and(|top| top.chars().eq(target.iter().copied()))
+            {
+                return Search::Found(path_to(&parents, node));
+            }
+            if depth + misplaced_tops(&state, &target).max(1) > max_depth {
                 continue;
-            };
+            }
+            let ids: Vec<i32> = state.stacks.keys().copied().collect();
+            for &from in &ids {
+                for &to in ids.iter().filter(|&&to| to != from) {
+                    for count in 1..=state.len(from) {
+                        let move_ = Move {
+                            move_: count,
+                            from,
+                            to,
+                        };
+                        let mut reached = state.clone();
+                        if reached
+                            .apply_move(crane, depth, &move_, MovePolicy::Abort)
+                            .is_err()
+                        {
+                            continue;
+                        }
+                        reached.history = History::default();
+                        if !visited.insert(reached.state_key(depth + 1)) {
+                            continue;
+                        }
+                        if parents.len() == max_states {
+                            return Search::GaveUp(max_states);
+                        }
+                        parents.push((node, Some(move_)));
+                        next.push((parents.len() - 1, reached));
+                    }
+                }
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+    Search::Exhausted
+}
 
-            self.stacks
-                .entry(move_.to)
-                .or_default()
-                .extend(crates_to_move);
+fn path_to(parents: &[(usize, Option<Move>)], mut node: usize) -> Vec<Move> {
+    let mut path = Vec::new();
+    while let (parent, Some(move_)) = &parents[node] {
+        path.push(move_.clone());
+        node = *parent;
+    }
+    path.reverse();
+    path
+}
+
+// Cranes are only handed moves that the source stack can satisfy
+trait Crane {
+    fn apply(&self, stack: &mut Stack, idx: usize, move_: &Move);
+}
+
+// Moves crates one at a time
+struct CrateMover9000;
+// Moves all crates of a move at once, keeping their order
+struct CrateMover9001;
+// Moves crates in batches of at most `capacity`, keeping each batch's order
+struct CappedCrane {
+    capacity: usize,
+}
+// Behaves like the 9001 but flips every other batch it carries
+struct AlternatingCrane;
+
+impl Crane for CrateMover9000 {
+    fn apply(&self, stack: &mut Stack, _idx: usize, move_: &Move) {
+        for _ in 0..move_.move_ {
+            let crate_ = stack.take(move_.from, 1);
+            stack.put(move_.to, crate_);
         }
     }
 }
 
-#[derive(Clone)]
+impl Crane for CrateMover9001 {
+    fn apply(&self, stack: &mut Stack, _idx: usize, move_: &Move) {
+        let crates = stack.take(move_.from, move_.move_);
+        stack.put(move_.to, crates);
+    }
+}
+
+impl Crane for CappedCrane {
+    fn apply(&self, stack: &mut Stack, _idx: usize, move_: &Move) {
+        let mut remaining = move_.move_;
+        while remaining > 0 {
+            let batch = remaining.min(self.capacity);
+            let crates = stack.take(move_.from, batch);
+            stack.put(move_.to, crates);
+            remaining -= batch;
+        }
+    }
+}
+
+impl Crane for AlternatingCrane {
+    fn apply(&self, stack: &mut Stack, idx: usize, move_: &Move) {
+        let crates = stack.take(move_.from, move_.move_);
+        if idx % 2 == 1 {
+            stack.put(move_.to, crates.into_iter().rev());
+        } else {
+            stack.put(move_.to, crates);
+        }
+    }
+}
+
+fn parse_crane(name: &str) -> Result<Box<dyn Crane>, CraneError> {
+    match name.split_once(':') {
+        None if name == "9000" => Ok(Box::new(CrateMover9000)),
+        None if name == "9001" => Ok(Box::new(CrateMover9001)),
+        None if name == "alternating" => Ok(Box::new(AlternatingCrane)),
+        Some(("capped", capacity)) => match capacity.parse() {
+            Ok(capacity) if capacity > 0 => Ok(Box::new(CappedCrane { capacity })),
+            _ => Err(CraneError),
+        },
+        _ => Err(CraneError),
+    }
+}
+
+#[derive(Clone, Debug)]
 struct Move {
-    move_: i32,
+    move_: usize,
     from: i32,
     to: i32,
 }
@@ -128,7 +718,7 @@ impl FromStr for Move {
             return Err(MoveError);
         };
         Ok(Move {
-            move_: move_.parse::<i32>().or(Err(MoveError))?,
+            move_: move_.parse::<usize>().or(Err(MoveError))?,
             from: from.parse::<i32>().or(Err(MoveError))?,
             to: to.parse::<i32>().or(Err(MoveError))?,
         })
@@ -140,13 +730,169 @@ impl FromStr for Moves {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let moves = s
             .lines()
-            .filter_map(|line| line.parse().ok())
-            .collect::<Vec<Move>>();
+            .filter(|line| !line.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Move>, _>>()?;
 
         Ok(Moves { moves })
     }
 }
 
+const HIGHLIGHT: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+enum Key {
+    TogglePause,
+    Step,
+    Quit,
+}
+
+// Switches the terminal to unbuffered, silent input for the lifetime of the
+// value so single key presses reach the playback without waiting for Enter.
+// Signal keys are turned off too, so that Ctrl-C arrives as a key press and
+// quits through the same path, restoring the terminal on the way out.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enable() -> Self {
+        let _ = Command::new("stty")
+            .args(["-icanon", "-echo", "-isig"])
+            .stderr(Stdio::null())
+            .status();
+        RawTerminal
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = Command::new("stty")
+            .args(["icanon", "echo", "isig"])
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+// Spawned once for the whole run: the thread holds the stdin lock until the
+// next key press, so a second reader would lose that key
+fn read_keys() -> Receiver<Key> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            let key = match byte {
+                Ok(b' ') | Ok(b'p') => Key::TogglePause,
+                Ok(b'n') | Ok(b's') => Key::Step,
+                // Ctrl-C, with the terminal's signal keys off
+                Ok(b'q') | Ok(0x03) => Key::Quit,
+                Ok(_) => continue,
+                Err(_) => break,
+            };
+            if sender.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+struct Playback {
+    keys: Receiver<Key>,
+    delay: Duration,
+    paused: bool,
+}
+
+impl Playback {
+    // Waits for the next frame: the delay while playing, or a step key while
+    // paused. Returns false once the viewer asked to quit.
+    fn wait(&mut self) -> bool {
+        let deadline = Instant::now() + self.delay;
+        loop {
+            let key = if self.paused {
+                match self.keys.recv() {
+                    Ok(key) => key,
+                    // Nobody left to unpause us
+                    Err(_) => {
+                        self.paused = false;
+                        continue;
+                    }
+                }
+            } else {
+                match self
+                    .keys
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    Ok(key) => key,
+                    Err(RecvTimeoutError::Timeout) => return true,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        return true;
+                    }
+                }
+            };
+            match key {
+                Key::TogglePause => {
+                    self.paused = !self.paused;
+                    if !self.paused {
+                        return true;
+                    }
+                }
+                Key::Step => {
+                    self.paused = true;
+                    return true;
+                }
+                Key::Quit => return false,
+            }
+        }
+    }
+
+    fn frame(&self, title: &str, stack: &Stack, highlight: (i32, usize)) {
+        let state = if self.paused { "paused" } else { "playing" };
+        print!(
+            "{CLEAR}{title}\n\n{}\n\n[{state}] space: pause/resume, n: step, q: quit\n",
+            stack.draw(Some(highlight))
+        );
+        let _ = io::stdout().flush();
+    }
+
+    // Replays the moves one at a time, showing the lifted crates on their
+    // source stack and then where they landed. Returns false if cut short.
+    fn play(
+        &mut self,
+        stack: &mut Stack,
+        crane: &dyn Crane,
+        moves: &[Move],
+        policy: MovePolicy,
+    ) -> Result<bool, InvalidMove> {
+        for (idx, move_) in moves.iter().enumerate() {
+            let title = format!(
+                "move {}/{}: move {} from {} to {}",
+                idx + 1,
+                moves.len(),
+                move_.move_,
+                move_.from,
+                move_.to
+            );
+            let lifted = (move_.move_).min(stack.len(move_.from));
+            self.frame(&title, stack, (move_.from, lifted));
+            if !self.wait() {
+                return Ok(false);
+            }
+
+            let landed_before = stack.len(move_.to);
+            let invalid = stack.apply_move(crane, idx, move_, policy)?;
+            let landed = stack.len(move_.to).saturating_sub(landed_before);
+            self.frame(&title, stack, (move_.to, landed));
+            if let Some(err) = invalid {
+                println!("{err}");
+            }
+            if !self.wait() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
 struct Program {
     stack: Stack,
     moves: Moves,
@@ -155,37 +901,385 @@ struct Program {
 impl FromStr for Program {
     type Err = ProgramError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (stack_lines, moves_lines) = s.split_once("\n\n").ok_or(ProgramError)?;
+        let (stack_lines, moves_lines) = s.split_once("\n\n").ok_or(ProgramError::MissingMoves)?;
 
         Ok(Program {
-            stack: stack_lines.parse::<Stack>().or(Err(ProgramError))?,
-            moves: moves_lines.parse::<Moves>().or(Err(ProgramError))?,
+            stack: stack_lines.parse::<Stack>().map_err(ProgramError::Stack)?,
+            moves: moves_lines.parse::<Moves>().or(Err(ProgramError::Moves))?,
         })
     }
 }
 
 fn main() -> io::Result<()> {
     let contents = read_to_string("input.txt")?;
-    let program = contents
-        .parse::<Program>()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to parse the input"))?;
-
-    // Part 1
-    let mut stack1 = program.stack.clone();
-    stack1.apply_moves(&program.moves.moves);
-    let part1 = stack1
-        .top_crates()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to get top crates"))?;
-
-    // Part 2
-    let mut stack2 = program.stack.clone();
-    stack2.apply_moves_part2(&program.moves.moves);
-    let part2 = stack2
-        .top_crates()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to get top crates"))?;
-
-    println!("{part1}");
-    println!("{part2}");
+    let program = contents.parse::<Program>().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to parse the input: {err}"),
+        )
+    })?;
+
+    let mut crane_names = Vec::new();
+    let mut policy = MovePolicy::Abort;
+    let mut dump = false;
+    let mut delay = None;
+    let mut after = None;
+    let mut bisect = false;
+    let mut solve_for = None;
+    let mut max_depth = 4;
+    let mut max_states = 100_000;
+    for arg in env::args().skip(1) {
+        if arg == "--dump" {
+            dump = true;
+        } else if let Some(target) = arg.strip_prefix("--solve=") {
+            solve_for = Some(target.to_string());
+        } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            max_depth = depth
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid search depth"))?;
+        } else if let Some(states) = arg.strip_prefix("--max-states=") {
+            max_states = states.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid number of states")
+            })?;
+        } else if arg == "--bisect" {
+            bisect = true;
+        } else if let Some(k) = arg.strip_prefix("--after=") {
+            after =
+                Some(k.parse::<usize>().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Invalid move count")
+                })?);
+        } else if arg == "--animate" {
+            delay = delay.or(Some(Duration::from_millis(500)));
+        } else if let Some(ms) = arg.strip_prefix("--delay=") {
+            let ms = ms.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid animation delay")
+            })?;
+            delay = Some(Duration::from_millis(ms));
+        } else if let Some(name) = arg.strip_prefix("--on-invalid=") {
+            policy = name.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Unknown policy, expected abort, skip or partial",
+                )
+            })?;
+        } else {
+            crane_names.push(arg);
+        }
+    }
+
+    let cranes = if crane_names.is_empty() {
+        vec![
+            Box::new(CrateMover9000) as Box<dyn Crane>,
+            Box::new(CrateMover9001),
+        ]
+    } else {
+        crane_names
+            .iter()
+            .map(|name| {
+                parse_crane(name).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Unknown crane, expected 9000, 9001, capped:<N> or alternating",
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+
+    // Shared by every crane's playback, see `read_keys`
+    let mut playback = delay.map(|delay| {
+        (
+            RawTerminal::enable(),
+            Playback {
+                keys: read_keys(),
+                delay,
+                paused: false,
+            },
+        )
+    });
+
+    let mut finals = Vec::new();
+    for crane in cranes {
+        if let Some(target) = &solve_for {
+            match solve(
+                &program.stack,
+                crane.as_ref(),
+                target,
+                max_depth,
+                max_states,
+            ) {
+                Search::Found(moves) => {
+                    for move_ in moves {
+                        println!("move {} from {} to {}", move_.move_, move_.from, move_.to);
+                    }
+                }
+                Search::Exhausted => println!("no solution within {max_depth} moves"),
+                Search::GaveUp(states) => {
+                    println!("gave up after {states} states without a solution, see --max-states")
+                }
+            }
+            println!();
+            continue;
+        }
+
+        let mut stack = program.stack.clone();
+        if let Some((_, playback)) = &mut playback {
+            let finished = playback
+                .play(&mut stack, crane.as_ref(), &program.moves.moves, policy)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            if !finished {
+                return Ok(());
+            }
+        } else if dump {
+            println!("{stack}\n");
+            for (idx, move_) in program.moves.moves.iter().enumerate() {
+                if let Some(err) = stack
+                    .apply_move(crane.as_ref(), idx, move_, policy)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+                {
+                    eprintln!("{err}");
+                }
+                println!("move {} from {} to {}", move_.move_, move_.from, move_.to);
+                println!("{stack}\n");
+            }
+        } else {
+            let invalid = stack
+                .apply_moves(crane.as_ref(), &program.moves.moves, policy)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            for err in invalid {
+                eprintln!("{err}");
+            }
+        }
+        if let Some(k) = after {
+            let state = stack.state_at(k).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Not that many moves applied")
+            })?;
+            println!("{state}\n");
+        }
+        let top = stack
+            .top_crates()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to get top crates"))?;
+        println!("{top}");
+        finals.push(stack);
+    }
+
+    if bisect {
+        let [a, b, ..] = finals.as_slice() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Bisecting needs at least two cranes",
+            ));
+        };
+        match first_divergence(a, b) {
+            Some(k) => {
+                let move_ = &program.moves.moves[k - 1];
+                println!(
+                    "first diverging move: #{k} (move {} from {} to {})",
+                    move_.move_, move_.from, move_.to
+                );
+                for state in [a, b].map(|stack| stack.state_at(k)).into_iter().flatten() {
+                    println!("{state}\n");
+                }
+            }
+            None => println!("no divergence"),
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses, renders and parses the rendering again, checking both parses
+    // agree and that rendering is stable. Returns the rendering.
+    fn round_trip(drawing: &str) -> String {
+        let stack: Stack = drawing.parse().unwrap();
+        let rendered = stack.to_string();
+        let again: Stack = rendered.parse().unwrap();
+        assert!(stack.stacks.keys().eq(again.stacks.keys()));
+        assert!(stack.same_crates(&again));
+        assert_eq!(again.to_string(), rendered);
+        rendered
+    }
+
+    #[test]
+    fn sample_drawing_renders_as_written() {
+        let drawing = "    [D]    \n[N] [C]    \n[Z] [M] [P]\n 1   2   3 ";
+        assert_eq!(round_trip(drawing), drawing);
+    }
+
+    #[test]
+    fn input_drawing_renders_as_written() {
+        let (drawing, _) = include_str!("../input.txt").split_once("\n\n").unwrap();
+        let rendered = round_trip(drawing);
+        assert!(rendered
+            .lines()
+            .map(str::trim_end)
+            .eq(drawing.lines().map(str::trim_end)));
+    }
+
+    #[test]
+    fn empty_stacks_keep_their_column() {
+        assert_eq!(round_trip(" 1   2   3 "), " 1   2   3 ");
+        assert_eq!(
+            round_trip("    [A]    \n 1   2   3 "),
+            "    [A]    \n 1   2   3 "
+        );
+        let stack: Stack = "[A]\n 1   2   3 ".parse().unwrap();
+        assert_eq!(stack.len(2), 0);
+        assert_eq!(stack.len(3), 0);
+        assert_eq!(stack.to_string(), "[A]        \n 1   2   3 ");
+    }
+
+    #[test]
+    fn long_labels_and_many_stacks() {
+        let drawing = "\
+[AB]                                          [LONG]
+[CD] [E] [F] [G] [H] [I] [J] [K] [M]  [N]     [O]
+ 1    2   3   4   5   6   7   8   9   10      11";
+        let stack: Stack = drawing.parse().unwrap();
+        assert_eq!(stack.stacks.len(), 11);
+        assert_eq!(stack.top_crates().unwrap(), "ABEFGHIJKMNLONG");
+        let rendered = round_trip(drawing);
+        assert_eq!(
+            rendered,
+            "\
+[AB]                                      [LONG]
+[CD] [E] [F] [G] [H] [I] [J] [K] [M] [N]   [O]  
+ 1    2   3   4   5   6   7   8   9   10    11  "
+        );
+    }
+
+    #[test]
+    fn moves_render_between_drawings() {
+        let mut stack: Stack = "    [D]    \n[N] [C]    \n[Z] [M] [P]\n 1   2   3 "
+            .parse()
+            .unwrap();
+        let move_ = Move {
+            move_: 1,
+            from: 2,
+            to: 1,
+        };
+        stack
+            .apply_move(&CrateMover9000, 0, &move_, MovePolicy::Abort)
+            .unwrap();
+        assert_eq!(
+            round_trip(&stack.to_string()),
+            "[D]        \n[N] [C]    \n[Z] [M] [P]\n 1   2   3 "
+        );
+    }
+
+    const SAMPLE: &str = "    [D]    \n[N] [C]    \n[Z] [M] [P]\n 1   2   3 ";
+
+    fn moves(text: &str) -> Vec<Move> {
+        text.parse::<Moves>().ok().unwrap().moves
+    }
+
+    fn cranes() -> Vec<Box<dyn Crane>> {
+        ["9000", "9001", "capped:2", "alternating"]
+            .iter()
+            .map(|name| parse_crane(name).ok().unwrap())
+            .collect()
+    }
+
+    // The second and third moves ask for more crates than their source holds
+    const MOVES: &str = "move 1 from 2 to 1\nmove 5 from 1 to 3\nmove 3 from 2 to 1\nmove 2 from 1 to 2\nmove 1 from 3 to 2\n";
+
+    #[test]
+    fn undo_and_redo_retrace_every_move() {
+        for crane in cranes() {
+            for policy in [MovePolicy::Skip, MovePolicy::Partial] {
+                let mut stack: Stack = SAMPLE.parse().unwrap();
+                let mut states = vec![stack.stacks.clone()];
+                for (idx, move_) in moves(MOVES).iter().enumerate() {
+                    stack
+                        .apply_move(crane.as_ref(), idx, move_, policy)
+                        .unwrap();
+                    states.push(stack.stacks.clone());
+                }
+                assert_eq!(stack.position(), states.len() - 1);
+
+                for expected in states.iter().rev().skip(1) {
+                    assert!(stack.undo());
+                    assert!(stack.stacks == *expected);
+                }
+                assert!(!stack.undo());
+                for expected in states.iter().skip(1) {
+                    assert!(stack.redo());
+                    assert!(stack.stacks == *expected);
+                }
+                assert!(!stack.redo());
+            }
+        }
+    }
+
+    #[test]
+    fn a_new_move_drops_the_undone_ones() {
+        let mut stack: Stack = SAMPLE.parse().unwrap();
+        let moves = moves(MOVES);
+        for (idx, move_) in moves.iter().enumerate().take(3) {
+            stack
+                .apply_move(&CrateMover9001, idx, move_, MovePolicy::Skip)
+                .unwrap();
+        }
+        stack.undo();
+        stack.undo();
+        stack
+            .apply_move(&CrateMover9001, 1, &moves[3], MovePolicy::Skip)
+            .unwrap();
+        assert_eq!(stack.position(), 2);
+        assert!(!stack.redo());
+        assert!(!stack.seek(3));
+    }
+
+    #[test]
+    fn state_at_matches_a_fresh_replay() {
+        let moves = moves(MOVES);
+        for crane in cranes() {
+            let mut stack: Stack = SAMPLE.parse().unwrap();
+            stack
+                .apply_moves(crane.as_ref(), &moves, MovePolicy::Partial)
+                .unwrap();
+            for k in 0..=moves.len() {
+                let mut fresh: Stack = SAMPLE.parse().unwrap();
+                fresh
+                    .apply_moves(crane.as_ref(), &moves[..k], MovePolicy::Partial)
+                    .unwrap();
+                let state = stack.state_at(k).unwrap();
+                assert!(state.stacks == fresh.stacks);
+                assert_eq!(state.position(), k);
+            }
+            assert!(stack.state_at(moves.len() + 1).is_none());
+            // Seeking a copy leaves the original where it was
+            assert_eq!(stack.position(), moves.len());
+        }
+    }
+
+    fn run(crane: &dyn Crane, drawing: &str, text: &str) -> Stack {
+        let mut stack: Stack = drawing.parse().unwrap();
+        stack
+            .apply_moves(crane, &moves(text), MovePolicy::Abort)
+            .unwrap();
+        stack
+    }
+
+    #[test]
+    fn first_divergence_between_cranes() {
+        let text =
+            "move 1 from 2 to 1\nmove 3 from 1 to 3\nmove 2 from 2 to 1\nmove 1 from 1 to 2\n";
+        let a = run(&CrateMover9000, SAMPLE, text);
+        let b = run(&CrateMover9001, SAMPLE, text);
+        assert_eq!(first_divergence(&a, &b), Some(2));
+        assert_eq!(first_divergence(&a, &a), None);
+
+        // Reversing twice lines the two cranes up again
+        let drawing = "[A]    \n[B]    \n[C]    \n 1   2 ";
+        let text = "move 2 from 1 to 2\nmove 2 from 2 to 1\n";
+        let a = run(&CrateMover9000, drawing, text);
+        let b = run(&CrateMover9001, drawing, text);
+        assert!(a.same_crates(&b));
+        assert_eq!(first_divergence(&a, &b), Some(1));
+    }
+}
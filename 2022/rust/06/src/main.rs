@@ -1,18 +1,368 @@
-use std::{collections::HashSet, fs::read_to_string};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    fs::read_to_string,
+    io::{self, BufReader, Read},
+    net::TcpStream,
+    time::Instant,
+};
 
-fn find_marker(contents: &str, n: usize) -> Option<usize> {
-    contents
-        .as_bytes()
-        .windows(n)
+use unicode_segmentation::UnicodeSegmentation;
+
+// Byte counts of the current window, along with how many distinct bytes
+// that makes
+struct Window {
+    counts: [usize; 256],
+    distinct: usize,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            counts: [0; 256],
+            distinct: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.counts[byte as usize] += 1;
+        if self.counts[byte as usize] == 1 {
+            self.distinct += 1;
+        }
+    }
+
+    fn pop(&mut self, byte: u8) {
+        self.counts[byte as usize] -= 1;
+        if self.counts[byte as usize] == 0 {
+            self.distinct -= 1;
+        }
+    }
+}
+
+// Positions right after every window of `n` distinct bytes. Only the last
+// `n` bytes are kept around, so the datastream can be of any length and is
+// only consumed as far as the markers are asked for.
+fn markers(data: impl IntoIterator<Item = u8>, n: usize) -> impl Iterator<Item = usize> {
+    let mut window = Window::new();
+    let mut recent = VecDeque::with_capacity(n + 1);
+    data.into_iter().enumerate().filter_map(move |(i, byte)| {
+        window.push(byte);
+        recent.push_back(byte);
+        if recent.len() > n {
+            window.pop(recent.pop_front()?);
+        }
+        (n > 0 && window.distinct == n).then_some(i + 1)
+    })
+}
+
+fn find_marker(data: impl IntoIterator<Item = u8>, n: usize) -> Option<usize> {
+    if n == 0 {
+        return Some(0);
+    }
+    markers(data, n).next()
+}
+
+// First marker position for every size from 1 up to the number of distinct
+// bytes in the datastream. Tracks the longest run of distinct bytes ending
+// at each position: whenever it grows past the longest seen so far, each
+// size in between gets its first marker there.
+fn first_markers(data: impl IntoIterator<Item = u8>) -> Vec<Option<usize>> {
+    let mut last_seen = [None; 256];
+    let mut alphabet = 0;
+    let mut start = 0;
+    let mut first = Vec::new();
+    for (i, byte) in data.into_iter().enumerate() {
+        match last_seen[byte as usize] {
+            Some(prev) => start = start.max(prev + 1),
+            None => alphabet += 1,
+        }
+        last_seen[byte as usize] = Some(i);
+        let run = i + 1 - start;
+        while first.len() < run {
+            first.push(Some(i + 1));
+        }
+    }
+    first.resize(alphabet, None);
+    first
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Char,
+    Grapheme,
+}
+
+// Where a marker ends, counted both in units of the datastream and in bytes
+struct UnicodeMarker {
+    index: usize,
+    byte_offset: usize,
+}
+
+// Same sliding window as `markers`, over chars or grapheme clusters instead
+// of bytes. The alphabet is unbounded so counts live in a map.
+fn find_unicode_marker(contents: &str, n: usize, unit: Unit) -> Option<UnicodeMarker> {
+    let symbols: Vec<(usize, &str)> = match unit {
+        Unit::Char => contents
+            .char_indices()
+            .map(|(offset, c)| (offset, &contents[offset..offset + c.len_utf8()]))
+            .collect(),
+        Unit::Grapheme => contents.grapheme_indices(true).collect(),
+    };
+    if n == 0 {
+        return Some(UnicodeMarker {
+            index: 0,
+            byte_offset: 0,
+        });
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (i, &(offset, symbol)) in symbols.iter().enumerate() {
+        *counts.entry(symbol).or_default() += 1;
+        if i >= n {
+            let old = symbols[i - n].1;
+            if let Some(count) = counts.get_mut(old) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(old);
+                }
+            }
+        }
+        if counts.len() == n {
+            return Some(UnicodeMarker {
+                index: i + 1,
+                byte_offset: offset + symbol.len(),
+            });
+        }
+    }
+    None
+}
+
+// Original version, kept as the baseline for the benchmark
+fn find_marker_hashset(data: &[u8], n: usize) -> Option<usize> {
+    data.windows(n)
         .position(|win| win.iter().collect::<HashSet<_>>().len() == n)
         .map(|i| i + n)
 }
 
-fn main() {
-    let contents = read_to_string("input.txt").expect("Failed to read file input");
-    let part_1 = find_marker(&contents, 4).unwrap_or(0);
-    let part_2 = find_marker(&contents, 14).unwrap_or(0);
+// Pseudo-random bytes from a fixed-seed xorshift, so every run is comparable
+fn bench_data(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+// Reads until the first marker shows up, from stdin or from a TCP socket,
+// and reports how fast the stream was consumed
+fn stream(n: usize, addr: Option<&str>) -> io::Result<()> {
+    let source: Box<dyn Read> = match addr {
+        Some(addr) => Box::new(TcpStream::connect(addr)?),
+        None => Box::new(io::stdin().lock()),
+    };
+    let mut read = 0;
+    let mut error = None;
+    let bytes = BufReader::new(source)
+        .bytes()
+        .map_while(|byte| byte.map_err(|err| error = Some(err)).ok())
+        .inspect(|_| read += 1);
+
+    let start = Instant::now();
+    let found = find_marker(bytes, n);
+    let elapsed = start.elapsed();
+    if let Some(err) = error {
+        return Err(err);
+    }
+
+    match found {
+        Some(position) => println!("{position}"),
+        None => println!("no marker in {read} bytes"),
+    }
+    eprintln!(
+        "read {read} bytes in {elapsed:?} ({:.1} MB/s)",
+        read as f64 / elapsed.as_secs_f64() / 1e6
+    );
+    Ok(())
+}
+
+fn bench() {
+    let data = bench_data(100_000);
+    println!(
+        "{:>6} {:>12} {:>12} {:>8}",
+        "size", "hashset", "sliding", "speedup"
+    );
+    for n in [1, 4, 14, 32, 64, 128, 192, 256] {
+        let start = Instant::now();
+        let expected = find_marker_hashset(&data, n);
+        let hashset = start.elapsed();
+
+        let start = Instant::now();
+        let found = find_marker(data.iter().copied(), n);
+        let sliding = start.elapsed();
+
+        assert_eq!(found, expected, "marker mismatch for size {n}");
+        println!(
+            "{n:>6} {:>12?} {:>12?} {:>7.1}x",
+            hashset,
+            sliding,
+            hashset.as_secs_f64() / sliding.as_secs_f64()
+        );
+    }
+}
+
+fn marker_size(args: &[String]) -> io::Result<usize> {
+    args.get(1)
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Expected a marker size"))
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("bench") => {
+            bench();
+            return Ok(());
+        }
+        Some("stream") => {
+            let n = marker_size(&args)?;
+            let addr = match &args[2..] {
+                [] => None,
+                [arg] => Some(arg.strip_prefix("--tcp=").ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unexpected argument {arg:?}, expected --tcp=<addr>"),
+                    )
+                })?),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Too many arguments, expected stream <size> [--tcp=<addr>]",
+                    ))
+                }
+            };
+            return stream(n, addr);
+        }
+        _ => {}
+    }
+
+    let contents = read_to_string("input.txt")?;
+    match args.first().map(String::as_str) {
+        Some("all") => {
+            for position in markers(contents.bytes(), marker_size(&args)?) {
+                println!("{position}");
+            }
+            return Ok(());
+        }
+        Some("unicode") => {
+            let n = marker_size(&args)?;
+            let unit = match args.get(2).map(String::as_str) {
+                None | Some("chars") => Unit::Char,
+                Some("graphemes") => Unit::Grapheme,
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Expected chars or graphemes",
+                    ))
+                }
+            };
+            match find_unicode_marker(&contents, n, unit) {
+                Some(marker) => {
+                    println!("index {}, byte offset {}", marker.index, marker.byte_offset)
+                }
+                None => println!("no marker"),
+            }
+            return Ok(());
+        }
+        Some("table") => {
+            println!("{:>4} {:>8}", "size", "marker");
+            for (size, position) in first_markers(contents.bytes()).iter().enumerate() {
+                match position {
+                    Some(position) => println!("{:>4} {position:>8}", size + 1),
+                    None => println!("{:>4} {:>8}", size + 1, "-"),
+                }
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let part_1 = find_marker(contents.bytes(), 4).unwrap_or(0);
+    let part_2 = find_marker(contents.bytes(), 14).unwrap_or(0);
 
     println!("{part_1}");
     println!("{part_2}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(contents: &str, n: usize, unit: Unit) -> Option<(usize, usize)> {
+        find_unicode_marker(contents, n, unit).map(|m| (m.index, m.byte_offset))
+    }
+
+    #[test]
+    fn chars_match_bytes_on_ascii() {
+        let contents = "mjqjpqmgbljsphdztnvjfqwrcgsmlb";
+        assert_eq!(marker(contents, 4, Unit::Char), Some((7, 7)));
+        assert_eq!(marker(contents, 4, Unit::Grapheme), Some((7, 7)));
+        assert_eq!(find_marker(contents.bytes(), 4), Some(7));
+    }
+
+    #[test]
+    fn chars_count_multibyte_characters_once() {
+        // 'é' and 'ü' are two bytes each, '€' three
+        assert_eq!(marker("ééüé€a", 3, Unit::Char), Some((5, 11)));
+    }
+
+    #[test]
+    fn combining_marks_stay_with_their_base() {
+        // "e" + U+0301 is a single é, distinct from the plain "e"
+        let contents = "e\u{301}e\u{301}ex";
+        assert_eq!(marker(contents, 2, Unit::Char), Some((2, 3)));
+        assert_eq!(marker(contents, 3, Unit::Grapheme), Some((4, 8)));
+    }
+
+    #[test]
+    fn devanagari_clusters() {
+        // न, म and स्ते: the virama and the vowel sign stay in the cluster
+        assert_eq!(marker("नमस्ते", 3, Unit::Char), Some((3, 9)));
+        assert_eq!(marker("नमस्ते", 3, Unit::Grapheme), Some((3, 18)));
+    }
+
+    #[test]
+    fn zwj_emoji_sequences_are_one_cluster() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let contents = format!("{family}{family}\u{1F468}");
+        assert_eq!(marker(&contents, 2, Unit::Char), Some((2, 7)));
+        assert_eq!(marker(&contents, 2, Unit::Grapheme), Some((3, 40)));
+    }
+
+    #[test]
+    fn flags_pair_their_regional_indicators() {
+        // France, France, Germany: the second pair repeats the first
+        let contents = "\u{1F1EB}\u{1F1F7}\u{1F1EB}\u{1F1F7}\u{1F1E9}\u{1F1EA}";
+        assert_eq!(marker(contents, 2, Unit::Char), Some((2, 8)));
+        assert_eq!(marker(contents, 2, Unit::Grapheme), Some((3, 24)));
+    }
+
+    #[test]
+    fn hangul_jamo_form_syllables() {
+        // 한 spelled out as three jamo, then a lone leading ᄀ
+        let contents = "\u{1112}\u{1161}\u{11AB}\u{1100}";
+        assert_eq!(marker(contents, 2, Unit::Char), Some((2, 6)));
+        assert_eq!(marker(contents, 2, Unit::Grapheme), Some((2, 12)));
+        assert_eq!(marker("한\u{1100}", 2, Unit::Grapheme), Some((2, 6)));
+    }
+
+    #[test]
+    fn crlf_is_one_cluster() {
+        assert_eq!(marker("\r\n\r\na", 2, Unit::Grapheme), Some((3, 5)));
+    }
 }
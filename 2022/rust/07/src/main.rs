@@ -1,34 +1,90 @@
-use std::{cell::RefCell, fs::read_to_string, io, rc::Rc, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fmt,
+    fs::{self, read_to_string},
+    io,
+    iter::{Enumerate, Peekable},
+    path::Path,
+    str::FromStr,
+    str::Lines,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 struct OperationError;
 struct ContentError;
 struct FileError;
 struct DirError;
 struct StateError;
+struct SizeError;
 
-#[derive(Clone)]
-struct Dir {
-    name: String,
-    contents: Rc<RefCell<Vec<Content>>>,
-    size: i32,
+#[derive(Clone, Copy)]
+enum Level {
+    Off,
+    Error,
+    Info,
+    Debug,
+    Trace,
 }
 
-impl std::fmt::Debug for Dir {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Dir")
-            .field("name", &self.name)
-            .field(
-                "contents",
-                &self
-                    .contents
-                    .borrow()
-                    .iter()
-                    .map(|content| format!("{:?}", content))
-                    .collect::<Vec<_>>(),
-            )
-            .field("size", &self.size)
-            .finish()
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Error as u8);
+
+impl FromStr for Level {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Level {
+    fn enabled(self) -> bool {
+        self as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
     }
+
+    // Starts from `DAY07_LOG` when set, then each `-v` raises the level by
+    // one and `-q` silences everything
+    fn init(args: &[String]) {
+        let mut level = env::var("DAY07_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(Level::Error) as u8;
+        for arg in args {
+            match arg.as_str() {
+                "-q" => level = Level::Off as u8,
+                "-v" | "-vv" | "-vvv" => level += arg.len() as u8 - 1,
+                _ => {}
+            }
+        }
+        LOG_LEVEL.store(level.min(Level::Trace as u8), Ordering::Relaxed);
+    }
+}
+
+// Diagnostics go to stderr so stdout only carries the answers
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $level.enabled() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// Index of a node in the `FileSystem` arena
+type NodeId = usize;
+
+// Transcript lines along with their 0-based index
+type Transcript<'a> = Peekable<Enumerate<Lines<'a>>>;
+
+#[derive(Debug)]
+struct Dir {
+    name: String,
+    contents: Vec<NodeId>,
+    size: u64,
 }
 
 impl FromStr for Dir {
@@ -41,7 +97,7 @@ impl FromStr for Dir {
         if f_type == "dir" {
             Ok(Dir {
                 name: String::from(f_name),
-                contents: Rc::new(RefCell::new(Vec::new())),
+                contents: Vec::new(),
                 size: 0,
             })
         } else {
@@ -50,28 +106,10 @@ impl FromStr for Dir {
     }
 }
 
-impl Dir {
-    fn calculate_dir_size(&mut self) -> i32 {
-        let size: i32 = self
-            .contents
-            .as_ref()
-            .borrow_mut()
-            .iter_mut()
-            .fold(0, |acc, c| {
-                acc + match c {
-                    Content::File(f) => f.size,
-                    Content::Dir(d) => d.calculate_dir_size(),
-                }
-            });
-        self.size = size;
-        size
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct File {
     name: String,
-    size: i32,
+    size: u64,
 }
 
 impl FromStr for File {
@@ -88,7 +126,7 @@ impl FromStr for File {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 enum Content {
     Dir(Dir),
     File(File),
@@ -107,284 +145,1517 @@ impl FromStr for Content {
     }
 }
 
-#[derive(Clone)]
+impl Content {
+    fn name(&self) -> &str {
+        match self {
+            Content::Dir(d) => &d.name,
+            Content::File(f) => &f.name,
+        }
+    }
+}
+
+struct Node {
+    parent: Option<NodeId>,
+    content: Content,
+    // Transcript line where the node first showed up, 0 for the root
+    line: usize,
+    // Whether the directory's own contents have been listed
+    listed: bool,
+    // Set for directories only known from a `cd` or `ls` path while
+    // validating, until a listing of their parent shows them
+    implied: bool,
+}
+
+// Every directory and file lives in `nodes` exactly once, and refers to its
+// parent and children by index, so there is a single authoritative tree
+struct FileSystem {
+    nodes: Vec<Node>,
+}
+
+impl FileSystem {
+    const ROOT: NodeId = 0;
+
+    fn new() -> Self {
+        FileSystem {
+            nodes: vec![Node {
+                parent: None,
+                content: Content::Dir(Dir {
+                    name: String::from("/"),
+                    contents: Vec::new(),
+                    size: 0,
+                }),
+                line: 0,
+                listed: false,
+                implied: false,
+            }],
+        }
+    }
+
+    fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id].parent
+    }
+
+    fn contents(&self, id: NodeId) -> &[NodeId] {
+        match &self.nodes[id].content {
+            Content::Dir(d) => &d.contents,
+            Content::File(_) => &[],
+        }
+    }
+
+    fn child(&self, dir: NodeId, name: &str) -> Option<NodeId> {
+        self.contents(dir)
+            .iter()
+            .copied()
+            .find(|&id| self.nodes[id].content.name() == name)
+    }
+
+    fn child_dir(&self, dir: NodeId, name: &str) -> Option<NodeId> {
+        self.child(dir, name)
+            .filter(|&id| matches!(self.nodes[id].content, Content::Dir(_)))
+    }
+
+    // Adds the content to the directory, unless something with the same name
+    // is already there, in which case that is returned instead. Directory
+    // totals up to the root are kept current, so adding fails rather than
+    // let any of them overflow
+    fn add(&mut self, dir: NodeId, content: Content, line: usize) -> Result<NodeId, SizeError> {
+        if let Some(existing) = self.child(dir, content.name()) {
+            return Ok(existing);
+        }
+        let size = match &content {
+            Content::Dir(d) => d.size,
+            Content::File(f) => f.size,
+        };
+        // The root holds the largest total, so checking it covers the rest
+        self.size(FileSystem::ROOT)
+            .checked_add(size)
+            .ok_or(SizeError)?;
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            parent: Some(dir),
+            content,
+            line,
+            listed: false,
+            implied: false,
+        });
+        if let Content::Dir(d) = &mut self.nodes[dir].content {
+            d.contents.push(id);
+        }
+        let mut current = Some(dir);
+        while let Some(ancestor) = current {
+            if let Content::Dir(d) = &mut self.nodes[ancestor].content {
+                d.size += size;
+            }
+            current = self.parent(ancestor);
+        }
+        Ok(id)
+    }
+
+    // Recomputes the total of a directory from scratch, ignoring the cache
+    #[cfg(test)]
+    fn calculate_dir_size(&self, id: NodeId) -> Result<u64, SizeError> {
+        let mut size: u64 = 0;
+        for &child in self.contents(id) {
+            let child_size = match &self.nodes[child].content {
+                Content::File(f) => f.size,
+                Content::Dir(_) => self.calculate_dir_size(child)?,
+            };
+            size = size.checked_add(child_size).ok_or(SizeError)?;
+        }
+        Ok(size)
+    }
+
+    // Sizes of every directory
+    fn dir_sizes(&self) -> Vec<u64> {
+        self.nodes
+            .iter()
+            .filter_map(|node| match &node.content {
+                Content::Dir(d) => Some(d.size),
+                Content::File(_) => None,
+            })
+            .collect()
+    }
+
+    // Size of a file, or the total of everything under a directory
+    fn size(&self, id: NodeId) -> u64 {
+        match &self.nodes[id].content {
+            Content::Dir(d) => d.size,
+            Content::File(f) => f.size,
+        }
+    }
+
+    fn path(&self, id: NodeId) -> String {
+        let mut names = Vec::new();
+        let mut current = id;
+        while let Some(parent) = self.parent(current) {
+            names.push(self.nodes[current].content.name());
+            current = parent;
+        }
+        names.reverse();
+        format!("/{}", names.join("/"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum Operations {
-    CD(String),
-    LS,
+    Cd(String),
+    Ls(Option<String>),
+    Pwd,
 }
 
 impl FromStr for Operations {
     type Err = OperationError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = s.split_whitespace().collect::<Vec<_>>();
-        if parts.len() == 3 {
-            Ok(Operations::CD(String::from(parts[2])))
-        } else if parts.len() == 2 {
-            Ok(Operations::LS)
+        match parts.as_slice() {
+            ["$", "cd", path] => Ok(Operations::Cd(String::from(*path))),
+            ["$", "ls"] => Ok(Operations::Ls(None)),
+            ["$", "ls", path] => Ok(Operations::Ls(Some(String::from(*path)))),
+            ["$", "pwd"] => Ok(Operations::Pwd),
+            _ => Err(OperationError),
+        }
+    }
+}
+
+impl FileSystem {
+    // Follows a path made of directory names, `.` and `..`, starting from the
+    // root when it begins with `/`. Going up from the root stays there.
+    fn resolve(&self, from: NodeId, path: &str) -> Option<NodeId> {
+        let start = if path.starts_with('/') {
+            FileSystem::ROOT
         } else {
-            Err(OperationError)
+            from
+        };
+        path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .try_fold(start, |dir, segment| match segment {
+                "." => Some(dir),
+                ".." => Some(self.parent(dir).unwrap_or(FileSystem::ROOT)),
+                name => self.child_dir(dir, name),
+            })
+    }
+}
+
+// Places where a transcript contradicts itself. Line numbers are 1-based.
+enum Conflict {
+    SizeChanged {
+        line: usize,
+        path: String,
+        first_line: usize,
+        old: u64,
+        new: u64,
+    },
+    KindChanged {
+        line: usize,
+        path: String,
+        first_line: usize,
+    },
+    Disappeared {
+        line: usize,
+        path: String,
+        first_line: usize,
+    },
+    UnknownDir {
+        line: usize,
+        path: String,
+    },
+    WrongPwd {
+        line: usize,
+        printed: String,
+        actual: String,
+    },
+    Unlisted {
+        line: usize,
+        path: String,
+    },
+}
+
+impl Conflict {
+    fn line(&self) -> usize {
+        match self {
+            Conflict::SizeChanged { line, .. }
+            | Conflict::KindChanged { line, .. }
+            | Conflict::Disappeared { line, .. }
+            | Conflict::UnknownDir { line, .. }
+            | Conflict::WrongPwd { line, .. }
+            | Conflict::Unlisted { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::SizeChanged {
+                line,
+                path,
+                first_line,
+                old,
+                new,
+            } => write!(
+                f,
+                "line {line}: {path} has size {new}, but {old} on line {first_line}"
+            ),
+            Conflict::KindChanged {
+                line,
+                path,
+                first_line,
+            } => write!(
+                f,
+                "line {line}: {path} is listed as both a file and a directory (line {first_line})"
+            ),
+            Conflict::Disappeared {
+                line,
+                path,
+                first_line,
+            } => write!(
+                f,
+                "line {line}: {path} is missing from this listing, but was listed on line {first_line}"
+            ),
+            Conflict::UnknownDir { line, path } => {
+                write!(f, "line {line}: {path} was never listed by its parent")
+            }
+            Conflict::WrongPwd {
+                line,
+                printed,
+                actual,
+            } => write!(f, "line {line}: pwd printed {printed}, expected {actual}"),
+            Conflict::Unlisted { line, path } => {
+                write!(f, "line {line}: contents of {path} are never listed")
+            }
         }
     }
 }
 
-#[derive(Clone)]
 struct State {
-    root: Rc<RefCell<Dir>>,
-    current_path: Vec<Rc<RefCell<Dir>>>,
+    fs: FileSystem,
+    cwd: NodeId,
+    // When set, contradictions are recorded in `conflicts` and the replay
+    // carries on instead of failing
+    validate: bool,
+    conflicts: Vec<Conflict>,
 }
 
 impl State {
-    fn calculate_total_size(&self) -> i32 {
-        self.root.as_ref().borrow_mut().calculate_dir_size()
+    fn new() -> Self {
+        State {
+            fs: FileSystem::new(),
+            cwd: FileSystem::ROOT,
+            validate: false,
+            conflicts: Vec::new(),
+        }
     }
 
-    fn path_string(&self) -> String {
-        let path: Vec<String> = self
-            .current_path
-            .iter()
-            .skip(1) // Skip root dir since we'll add the initial / anyway
-            .map(|d| d.borrow().name.clone())
-            .collect();
+    // Replays a terminal transcript, building up the filesystem
+    fn run(&mut self, transcript: &str) -> io::Result<()> {
+        let mut lines = transcript.lines().enumerate().peekable();
+        while let Some((idx, line)) = lines.next() {
+            if !line.starts_with('$') {
+                continue;
+            }
+            let op = line
+                .parse::<Operations>()
+                .map_err(|_| io::Error::other(format!("Invalid operation: {line}")))?;
 
-        if path.is_empty() {
-            "/".to_string()
-        } else {
-            format!("/{}", path.join("/"))
+            match op {
+                Operations::Cd(path) => {
+                    self.cd(&path, idx + 1)
+                        .map_err(|_| io::Error::other("CD operation failed"))?;
+                }
+                Operations::Ls(path) => {
+                    self.ls(path.as_deref(), idx + 1, &mut lines)
+                        .map_err(|_| io::Error::other("LS operation failed"))?;
+                }
+                Operations::Pwd => {
+                    self.pwd(&mut lines)
+                        .map_err(|_| io::Error::other("PWD operation failed"))?;
+                }
+            }
         }
+        Ok(())
     }
 
-    fn add_contents(&mut self, contents: Vec<Content>) -> Result<(), StateError> {
-        if let Some(current_dir) = self.current_path.last() {
-            // Get current contents
-            let current_dir_ref = current_dir.borrow_mut();
-            let mut current_contents = current_dir_ref.contents.borrow_mut();
+    // Replays the transcript collecting every contradiction in it, followed
+    // by the directories it never looked into
+    fn check(transcript: &str) -> io::Result<Vec<Conflict>> {
+        let mut state = State::new();
+        state.validate = true;
+        state.run(transcript)?;
+        state.conflicts.sort_by_key(Conflict::line);
+        let unlisted = state
+            .fs
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node.content, Content::Dir(_)) && !node.listed)
+            .map(|(id, node)| Conflict::Unlisted {
+                line: node.line,
+                path: state.fs.path(id),
+            })
+            .collect::<Vec<_>>();
+        state.conflicts.extend(unlisted);
+        Ok(state.conflicts)
+    }
 
-            // For each new content item
-            for content in contents {
-                // Check if we already have this item
-                let exists = current_contents
-                    .iter()
-                    .any(|existing| match (existing, &content) {
-                        (Content::File(f1), Content::File(f2)) => f1.name == f2.name,
-                        (Content::Dir(d1), Content::Dir(d2)) => d1.name == d2.name,
-                        _ => false,
-                    });
-
-                // Only add if it doesn't exist
-                if !exists {
-                    current_contents.push(content);
-                }
-            }
+    // Finds the directory at `path`. While validating, a missing directory is
+    // reported and created so the replay can go on.
+    fn find_dir(&mut self, path: &str, line: usize) -> Result<NodeId, StateError> {
+        if let Some(id) = self.fs.resolve(self.cwd, path) {
+            return Ok(id);
+        }
+        if !self.validate {
+            log!(Level::Error, "Error: Directory '{}' not found.", path);
+            return Err(StateError);
+        }
 
-            Ok(())
+        let mut dir = if path.starts_with('/') {
+            FileSystem::ROOT
         } else {
-            Err(StateError)
+            self.cwd
+        };
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            dir = match segment {
+                "." => dir,
+                ".." => self.fs.parent(dir).unwrap_or(FileSystem::ROOT),
+                name => match self.fs.child_dir(dir, name) {
+                    Some(id) => id,
+                    None => {
+                        let id = self
+                            .fs
+                            .add(
+                                dir,
+                                Content::Dir(Dir {
+                                    name: String::from(name),
+                                    contents: Vec::new(),
+                                    size: 0,
+                                }),
+                                line,
+                            )
+                            .map_err(|_| StateError)?;
+                        self.fs.nodes[id].implied = true;
+                        self.conflicts.push(Conflict::UnknownDir {
+                            line,
+                            path: self.fs.path(id),
+                        });
+                        id
+                    }
+                },
+            };
         }
+        Ok(dir)
     }
 
-    fn ls(&mut self, lines: &mut std::str::Lines) -> Result<(), StateError> {
-        println!("\n=== LS ===");
-        println!("Current path before ls: {}", self.path_string());
-
-        let mut new_contents = Vec::new();
-        let current_dir = self.current_path.last().ok_or(StateError)?.clone();
-        {
-            let current_borrow = current_dir.borrow();
-            let current_contents = current_borrow.contents.borrow();
-            new_contents.extend(current_contents.iter().cloned());
+    // Merges a listed entry into the directory, recording any disagreement
+    // with what an earlier listing said about the same name
+    fn list_entry(&mut self, dir: NodeId, content: Content, line: usize) -> Result<(), SizeError> {
+        let Some(existing) = self.fs.child(dir, content.name()) else {
+            self.fs.add(dir, content, line)?;
+            return Ok(());
+        };
+        // The first listing to show an implied directory is where it was
+        // really listed from then on
+        let node = &mut self.fs.nodes[existing];
+        if node.implied && matches!(content, Content::Dir(_)) {
+            node.implied = false;
+            node.line = line;
+            return Ok(());
         }
-
-        while let Some(line) = lines.next() {
-            if line.starts_with('$') {
-                break;
+        let first_line = self.fs.nodes[existing].line;
+        let path = self.fs.path(existing);
+        match (&self.fs.nodes[existing].content, &content) {
+            (Content::File(old), Content::File(new)) if old.size != new.size => {
+                self.conflicts.push(Conflict::SizeChanged {
+                    line,
+                    path,
+                    first_line,
+                    old: old.size,
+                    new: new.size,
+                });
             }
-            println!("Parsing line: {}", line);
+            (Content::File(_), Content::Dir(_)) | (Content::Dir(_), Content::File(_)) => {
+                self.conflicts.push(Conflict::KindChanged {
+                    line,
+                    path,
+                    first_line,
+                });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn path_string(&self) -> String {
+        self.fs.path(self.cwd)
+    }
+
+    fn ls(
+        &mut self,
+        path: Option<&str>,
+        line_number: usize,
+        lines: &mut Transcript,
+    ) -> Result<(), StateError> {
+        log!(Level::Debug, "=== LS ===");
+        log!(
+            Level::Debug,
+            "Current path before ls: {}",
+            self.path_string()
+        );
+
+        let dir = match path {
+            Some(path) => self.find_dir(path, line_number)?,
+            None => self.cwd,
+        };
+
+        // Leave the next command for the caller
+        let mut seen = Vec::new();
+        while let Some((idx, line)) = lines.next_if(|(_, line)| !line.starts_with('$')) {
+            log!(Level::Trace, "Parsing line: {}", line);
             match line.parse::<Content>() {
                 Ok(content) => {
-                    println!("Parsed content: {:?}", content);
-                    let exists = new_contents
-                        .iter()
-                        .any(|existing| match (existing, &content) {
-                            (Content::File(f1), Content::File(f2)) => f1.name == f2.name,
-                            (Content::Dir(d1), Content::Dir(d2)) => d1.name == d2.name,
-                            _ => false,
-                        });
-
-                    if !exists {
-                        if let Content::Dir(dir) = content {
-                            // Create the directory with its own RefCell
-                            let new_dir = Rc::new(RefCell::new(Dir {
-                                name: dir.name,
-                                contents: Rc::new(RefCell::new(Vec::new())),
-                                size: 0,
-                            }));
-
-                            // Store the reference to it
-                            new_contents.push(Content::Dir(Dir {
-                                name: new_dir.borrow().name.clone(),
-                                contents: new_dir.borrow().contents.clone(),
-                                size: 0,
-                            }));
-                        } else {
-                            new_contents.push(content);
-                        }
+                    log!(Level::Trace, "Parsed content: {:?}", content);
+                    seen.push(String::from(content.name()));
+                    if self.list_entry(dir, content, idx + 1).is_err() {
+                        log!(
+                            Level::Error,
+                            "Error: '{}' overflows the size of '{}'.",
+                            line,
+                            self.fs.path(dir)
+                        );
+                        return Err(StateError);
                     }
                 }
                 Err(_) => {
-                    println!("Failed to parse line: {}", line);
+                    log!(Level::Error, "Failed to parse line: {}", line);
                     return Err(StateError);
                 }
             }
         }
 
-        // Update the directory's contents with merged list
-        let mut current_dir_ref = current_dir.borrow_mut();
-        *current_dir_ref.contents.borrow_mut() = new_contents.clone();
+        if self.fs.nodes[dir].listed {
+            let missing = self
+                .fs
+                .contents(dir)
+                .iter()
+                .filter(|&&id| {
+                    !self.fs.nodes[id].implied
+                        && !seen
+                            .iter()
+                            .any(|name| name == self.fs.nodes[id].content.name())
+                })
+                .map(|&id| Conflict::Disappeared {
+                    line: line_number,
+                    path: self.fs.path(id),
+                    first_line: self.fs.nodes[id].line,
+                })
+                .collect::<Vec<_>>();
+            self.conflicts.extend(missing);
+        }
+        self.fs.nodes[dir].listed = true;
 
-        println!("Contents of current directory after ls: {:?}", new_contents);
+        let names: Vec<&str> = self
+            .fs
+            .contents(dir)
+            .iter()
+            .map(|&id| self.fs.nodes[id].content.name())
+            .collect();
+        log!(
+            Level::Debug,
+            "Contents of {} after ls: {:?}",
+            self.fs.path(dir),
+            names
+        );
         Ok(())
     }
 
-    fn cd(&mut self, dir: &str) -> Result<(), StateError> {
-        println!("\n=== CD {} ===", dir);
-        println!("Current path before cd: {}", self.path_string());
+    // The transcript's own output for `pwd`, if any, has to match where we
+    // think we are
+    fn pwd(&mut self, lines: &mut Transcript) -> Result<(), StateError> {
+        let path = self.path_string();
+        log!(Level::Info, "Current path: {}", path);
+        while let Some((idx, line)) = lines.next_if(|(_, line)| !line.starts_with('$')) {
+            if line.trim() != path && self.validate {
+                self.conflicts.push(Conflict::WrongPwd {
+                    line: idx + 1,
+                    printed: String::from(line.trim()),
+                    actual: path.clone(),
+                });
+            } else if line.trim() != path {
+                log!(
+                    Level::Error,
+                    "Error: pwd printed '{}' but the current path is '{}'.",
+                    line.trim(),
+                    path
+                );
+                return Err(StateError);
+            }
+        }
+        Ok(())
+    }
+
+    fn cd(&mut self, path: &str, line: usize) -> Result<(), StateError> {
+        log!(Level::Debug, "=== CD {} ===", path);
+        log!(
+            Level::Debug,
+            "Current path before cd: {}",
+            self.path_string()
+        );
+
+        self.cwd = self.find_dir(path, line)?;
+        log!(
+            Level::Info,
+            "Moved into '{}'. Current path after cd: {}",
+            path,
+            self.path_string()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SortBy {
+    Name,
+    Size,
+}
+
+struct RenderOptions {
+    max_depth: usize,
+    sort_by: SortBy,
+    human: bool,
+}
+
+impl RenderOptions {
+    fn from_args(args: &[String]) -> io::Result<Self> {
+        let sort_by = match args.iter().find_map(|arg| arg.strip_prefix("--sort=")) {
+            None | Some("name") => SortBy::Name,
+            Some("size") => SortBy::Size,
+            Some(_) => return Err(io::Error::other("Expected --sort=name or --sort=size")),
+        };
+        Ok(RenderOptions {
+            max_depth: parse_arg(args, "--depth=", usize::MAX)?,
+            sort_by,
+            human: args.iter().any(|arg| arg == "-h"),
+        })
+    }
+
+    fn format_size(&self, size: u64) -> String {
+        if !self.human {
+            return size.to_string();
+        }
+        let mut value = size as f64;
+        let mut unit = "";
+        for next in ["K", "M", "G", "T"] {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+        match (unit, value < 10.0) {
+            ("", _) => size.to_string(),
+            (_, true) => format!("{value:.1}{unit}"),
+            (_, false) => format!("{value:.0}{unit}"),
+        }
+    }
+}
+
+impl FileSystem {
+    fn sorted_contents(&self, id: NodeId, sort_by: SortBy) -> Vec<NodeId> {
+        let mut contents = self.contents(id).to_vec();
+        match sort_by {
+            SortBy::Name => contents.sort_by_key(|&child| self.nodes[child].content.name()),
+            SortBy::Size => contents.sort_by_key(|&child| std::cmp::Reverse(self.size(child))),
+        }
+        contents
+    }
+
+    // Indented tree like `tree`, with the cumulative size of every entry
+    fn render_tree(&self, options: &RenderOptions) -> String {
+        let mut out = format!("/ ({})\n", options.format_size(self.size(FileSystem::ROOT)));
+        self.render_children(FileSystem::ROOT, "", 1, options, &mut out);
+        out
+    }
+
+    fn render_children(
+        &self,
+        id: NodeId,
+        prefix: &str,
+        depth: usize,
+        options: &RenderOptions,
+        out: &mut String,
+    ) {
+        if depth > options.max_depth {
+            return;
+        }
+        let contents = self.sorted_contents(id, options.sort_by);
+        for (idx, &child) in contents.iter().enumerate() {
+            let last = idx + 1 == contents.len();
+            let (branch, indent) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            let node = &self.nodes[child].content;
+            let suffix = if matches!(node, Content::Dir(_)) {
+                "/"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "{prefix}{branch}{}{suffix} ({})\n",
+                node.name(),
+                options.format_size(self.size(child))
+            ));
+            self.render_children(child, &format!("{prefix}{indent}"), depth + 1, options, out);
+        }
+    }
+
+    // Directory totals like `du`: every directory down to the maximum depth,
+    // each one listed after its subdirectories
+    fn render_du(&self, options: &RenderOptions) -> String {
+        let mut out = String::new();
+        self.render_du_dir(FileSystem::ROOT, 0, options, &mut out);
+        out
+    }
+
+    fn render_du_dir(&self, id: NodeId, depth: usize, options: &RenderOptions, out: &mut String) {
+        for child in self.sorted_contents(id, options.sort_by) {
+            if matches!(self.nodes[child].content, Content::Dir(_)) {
+                self.render_du_dir(child, depth + 1, options, out);
+            }
+        }
+        if depth <= options.max_depth {
+            out.push_str(&format!(
+                "{}\t{}\n",
+                options.format_size(self.size(id)),
+                self.path(id)
+            ));
+        }
+    }
+}
+
+impl FileSystem {
+    // Recreates the tree under `target`, which must be missing or empty.
+    // Files are created sparse, with their length set but nothing written.
+    fn materialize(&self, target: &Path) -> io::Result<()> {
+        if target.exists() && fs::read_dir(target)?.next().is_some() {
+            return Err(io::Error::other(format!(
+                "{} is not empty",
+                target.display()
+            )));
+        }
+        fs::create_dir_all(target)?;
+        self.materialize_dir(FileSystem::ROOT, target)
+    }
 
-        match dir {
-            ".." => {
-                if self.current_path.len() > 1 {
-                    self.current_path.pop();
+    fn materialize_dir(&self, id: NodeId, path: &Path) -> io::Result<()> {
+        for &child in self.contents(id) {
+            // Keep every entry inside the target directory
+            let name = self.nodes[child].content.name();
+            if matches!(name, "." | "..") || name.contains(['/', '\\']) {
+                return Err(io::Error::other(format!(
+                    "Refusing to create {}",
+                    self.path(child)
+                )));
+            }
+            match &self.nodes[child].content {
+                Content::Dir(d) => {
+                    let dir_path = path.join(&d.name);
+                    fs::create_dir(&dir_path)?;
+                    self.materialize_dir(child, &dir_path)?;
+                }
+                Content::File(f) => {
+                    fs::File::create(path.join(&f.name))?.set_len(f.size)?;
                 }
-                println!(
-                    "Moved up one level. Current path after cd: {}",
-                    self.path_string()
-                );
-                Ok(())
             }
-            "/" => {
-                self.current_path = vec![self.root.clone()];
-                println!("Moved to root directory. Current path after cd: /");
-                Ok(())
+        }
+        Ok(())
+    }
+}
+
+// Writes the `$ cd`/`$ ls` session that would explore a local directory,
+// visiting entries in name order. Symlinks are not followed, and names the
+// transcript format cannot hold (containing whitespace) are left out.
+fn transcript_from_dir(root: &Path) -> io::Result<String> {
+    let mut out = String::from("$ cd /\n");
+    explore_dir(root, &mut out)?;
+    Ok(out)
+}
+
+fn explore_dir(path: &Path, out: &mut String) -> io::Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        // A lossy conversion would name a different file, if any
+        let Ok(name) = entry.file_name().into_string() else {
+            log!(
+                Level::Error,
+                "Skipping '{}': the name is not valid UTF-8.",
+                entry.path().display()
+            );
+            continue;
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            log!(
+                Level::Error,
+                "Skipping '{}': the name cannot be written in a transcript.",
+                entry.path().display()
+            );
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            entries.push((name, None));
+        } else if file_type.is_file() {
+            entries.push((name, Some(entry.metadata()?.len())));
+        } else {
+            log!(
+                Level::Error,
+                "Skipping '{}': only files and directories can be written in a transcript.",
+                entry.path().display()
+            );
+        }
+    }
+    entries.sort();
+
+    out.push_str("$ ls\n");
+    for (name, size) in &entries {
+        match size {
+            Some(size) => out.push_str(&format!("{size} {name}\n")),
+            None => out.push_str(&format!("dir {name}\n")),
+        }
+    }
+    for (name, _) in entries.iter().filter(|(_, size)| size.is_none()) {
+        out.push_str(&format!("$ cd {name}\n"));
+        explore_dir(&path.join(name), out)?;
+        out.push_str("$ cd ..\n");
+    }
+    Ok(())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl FileSystem {
+    // Nested objects with the name, type and size of every entry, and the
+    // contents of every directory
+    fn to_json(&self, id: NodeId) -> String {
+        let node = &self.nodes[id].content;
+        let name = json_string(node.name());
+        let size = self.size(id);
+        match node {
+            Content::File(_) => format!(r#"{{"name":{name},"type":"file","size":{size}}}"#),
+            Content::Dir(d) => {
+                let contents = d
+                    .contents
+                    .iter()
+                    .map(|&child| self.to_json(child))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(r#"{{"name":{name},"type":"dir","size":{size},"contents":[{contents}]}}"#)
             }
-            target_dir => {
-                let current = self.current_path.last().ok_or(StateError)?.clone();
-                let current_borrow = current.borrow();
-                let contents = current_borrow.contents.borrow();
-
-                // Look in current directory first
-                let target = contents.iter().find_map(|content| {
-                    if let Content::Dir(d) = content {
-                        if d.name == target_dir {
-                            Some(Content::Dir(d.clone()))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                });
+        }
+    }
+}
 
-                drop(contents);
-                drop(current_borrow);
-
-                match target {
-                    Some(Content::Dir(dir)) => {
-                        // Navigate to the directory
-                        let dir_ref = Rc::new(RefCell::new(dir));
-                        self.current_path.push(dir_ref);
-                        println!(
-                            "Moved into directory '{}'. Current path after cd: {}",
-                            target_dir,
-                            self.path_string()
-                        );
-                        Ok(())
-                    }
-                    _ => {
-                        // Try root if not at root
-                        if self.current_path.len() > 1 {
-                            let root_borrow = self.root.borrow();
-                            let root_contents = root_borrow.contents.borrow();
-
-                            if let Some(Content::Dir(dir)) =
-                                root_contents.iter().find_map(|content| {
-                                    if let Content::Dir(d) = content {
-                                        if d.name == target_dir {
-                                            Some(Content::Dir(d.clone()))
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                })
-                            {
-                                drop(root_contents);
-                                drop(root_borrow);
-
-                                self.current_path = vec![self.root.clone()];
-                                let dir_ref = Rc::new(RefCell::new(dir));
-                                self.current_path.push(dir_ref);
-                                println!(
-                                    "Moved into directory '{}' from root. Current path after cd: {}",
-                                    target_dir,
-                                    self.path_string()
-                                );
-                                Ok(())
-                            } else {
-                                println!(
-                                    "Error: Directory '{}' not found in current directory or root.",
-                                    target_dir
-                                );
-                                Err(StateError)
-                            }
-                        } else {
-                            println!(
-                                "Error: Directory '{}' not found in current directory.",
-                                target_dir
-                            );
-                            Err(StateError)
-                        }
+// Shell-style wildcards: `?` matches one character and `*` any run of
+// characters except `/`, while `**` also crosses directories
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        ['?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != '/' && glob_match(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && glob_match(rest, tail)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    File,
+    Dir,
+}
+
+#[derive(Default)]
+struct Query {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    name: Option<Vec<char>>,
+    path: Option<Vec<char>>,
+    kind: Option<Kind>,
+}
+
+impl Query {
+    // Accepts both `--flag value` and `--flag=value`. Any other `--` flag is
+    // rejected rather than silently matching everything.
+    fn from_args(args: &[String]) -> io::Result<Self> {
+        let mut query = Query::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            if !matches!(
+                flag,
+                "--min-size" | "--max-size" | "--name" | "--path" | "--type"
+            ) {
+                if flag.starts_with("--") {
+                    return Err(io::Error::other(format!("Unknown option {flag}")));
+                }
+                continue;
+            }
+            let value = value
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| io::Error::other(format!("Missing value for {flag}")))?;
+            let size = || {
+                value
+                    .parse()
+                    .map_err(|_| io::Error::other(format!("Invalid size for {flag}")))
+            };
+            match flag {
+                "--min-size" => query.min_size = Some(size()?),
+                "--max-size" => query.max_size = Some(size()?),
+                "--name" => query.name = Some(value.chars().collect()),
+                "--path" => query.path = Some(value.chars().collect()),
+                _ => {
+                    query.kind = match value.as_str() {
+                        "f" | "file" => Some(Kind::File),
+                        "d" | "dir" => Some(Kind::Dir),
+                        _ => return Err(io::Error::other("Expected --type f or --type d")),
                     }
                 }
             }
         }
+        Ok(query)
+    }
+
+    fn matches(&self, fs: &FileSystem, id: NodeId) -> bool {
+        let node = &fs.nodes[id].content;
+        let kind = match node {
+            Content::File(_) => Kind::File,
+            Content::Dir(_) => Kind::Dir,
+        };
+        let size = fs.size(id);
+        let name: Vec<char> = node.name().chars().collect();
+        let path: Vec<char> = fs.path(id).chars().collect();
+        self.kind.is_none_or(|k| k == kind)
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self.name.as_ref().is_none_or(|p| glob_match(p, &name))
+            && self.path.as_ref().is_none_or(|p| glob_match(p, &path))
+    }
+
+    // Matching entries in tree order, each directory before its contents
+    fn find(&self, fs: &FileSystem) -> Vec<NodeId> {
+        let mut found = Vec::new();
+        let mut pending = vec![FileSystem::ROOT];
+        while let Some(id) = pending.pop() {
+            if self.matches(fs, id) {
+                found.push(id);
+            }
+            pending.extend(fs.contents(id).iter().rev());
+        }
+        found
+    }
+
+    fn largest(&self, fs: &FileSystem, n: usize) -> Vec<NodeId> {
+        let mut found = self.find(fs);
+        found.sort_by_key(|&id| std::cmp::Reverse(fs.size(id)));
+        found.truncate(n);
+        found
+    }
+}
+
+fn print_entries(fs: &FileSystem, ids: &[NodeId]) {
+    for &id in ids {
+        let suffix = match fs.nodes[id].content {
+            Content::Dir(_) if id != FileSystem::ROOT => "/",
+            _ => "",
+        };
+        println!("{}\t{}{suffix}", fs.size(id), fs.path(id));
+    }
+}
+
+// Every node keyed by its path, with directories ending in '/' so that a
+// file replaced by a directory shows up as one removal and one addition
+fn entries(fs: &FileSystem) -> BTreeMap<String, u64> {
+    (0..fs.nodes.len())
+        .map(|id| {
+            let path = match fs.nodes[id].content {
+                Content::Dir(_) if id != FileSystem::ROOT => format!("{}/", fs.path(id)),
+                _ => fs.path(id),
+            };
+            (path, fs.size(id))
+        })
+        .collect()
+}
+
+enum Change {
+    Added,
+    Removed,
+    Resized,
+}
+
+struct DiffLine {
+    change: Change,
+    path: String,
+    old: u64,
+    new: u64,
+}
+
+impl DiffLine {
+    fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+
+    fn status(&self) -> char {
+        match self.change {
+            Change::Added => 'A',
+            Change::Removed => 'D',
+            Change::Resized => 'M',
+        }
+    }
+
+    fn delta(&self) -> String {
+        if self.new >= self.old {
+            format!("+{}", self.new - self.old)
+        } else {
+            format!("-{}", self.old - self.new)
+        }
+    }
+}
+
+// Files and directories that appeared, disappeared or changed size between
+// the two trees, in path order
+fn diff(before: &FileSystem, after: &FileSystem) -> Vec<DiffLine> {
+    let before = entries(before);
+    let after = entries(after);
+    let paths: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (change, old, new) = match (before.get(path), after.get(path)) {
+                (None, Some(&new)) => (Change::Added, 0, new),
+                (Some(&old), None) => (Change::Removed, old, 0),
+                (Some(&old), Some(&new)) if old != new => (Change::Resized, old, new),
+                _ => return None,
+            };
+            Some(DiffLine {
+                change,
+                path: path.clone(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+// Laid out like `diff --stat`, with bars scaled to the largest change
+fn print_diff(lines: &[DiffLine]) {
+    const BAR_WIDTH: u128 = 40;
+    let path_width = lines
+        .iter()
+        .map(|line| line.path.chars().count())
+        .max()
+        .unwrap_or(0);
+    let delta_width = lines
+        .iter()
+        .map(|line| line.delta().len())
+        .max()
+        .unwrap_or(0);
+    let largest = lines
+        .iter()
+        .map(|line| line.old.abs_diff(line.new))
+        .max()
+        .unwrap_or(0);
+    for line in lines {
+        let status = line.status();
+        let magnitude = line.old.abs_diff(line.new);
+        let bar = if magnitude == 0 {
+            0
+        } else {
+            (magnitude as u128 * BAR_WIDTH / largest as u128).max(1) as usize
+        };
+        let mark = if line.new >= line.old { "+" } else { "-" };
+        let row = format!(
+            " {status} {:<path_width$} | {:>delta_width$} {}",
+            line.path,
+            line.delta(),
+            mark.repeat(bar)
+        );
+        println!("{}", row.trim_end());
+    }
+
+    let files: Vec<&DiffLine> = lines.iter().filter(|line| !line.is_dir()).collect();
+    let (mut added, mut removed, mut resized) = (0, 0, 0);
+    for line in &files {
+        match line.change {
+            Change::Added => added += 1,
+            Change::Removed => removed += 1,
+            Change::Resized => resized += 1,
+        }
+    }
+    let grown: u128 = files
+        .iter()
+        .map(|line| line.new.saturating_sub(line.old) as u128)
+        .sum();
+    let shrunk: u128 = files
+        .iter()
+        .map(|line| line.old.saturating_sub(line.new) as u128)
+        .sum();
+    println!(
+        " {} files changed ({added} added, {removed} removed, {resized} resized), {} directories changed, {grown} bytes added(+), {shrunk} bytes removed(-)",
+        files.len(),
+        lines.len() - files.len(),
+    );
+}
+
+const DISK_SIZE: u64 = 70_000_000;
+const REQUIRED_SPACE: u64 = 30_000_000;
+const SMALL_DIR_SIZE: u64 = 100_000;
+
+struct Capacity {
+    disk: u64,
+    used: u64,
+    free: u64,
+    // How far the transcript goes past the disk size, if it does
+    over: u64,
+    // Space to delete so that `required` is free afterwards
+    needed: u64,
+}
+
+impl Capacity {
+    fn new(disk: u64, used: u64, required: u64) -> Capacity {
+        let free = disk.saturating_sub(used);
+        let over = used.saturating_sub(disk);
+        Capacity {
+            disk,
+            used,
+            free,
+            over,
+            needed: required.saturating_sub(free).saturating_add(over),
+        }
+    }
+}
+
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "disk:   {}", self.disk)?;
+        writeln!(f, "used:   {}", self.used)?;
+        if self.over > 0 {
+            writeln!(f, "free:   0 (over capacity by {})", self.over)?;
+        } else {
+            writeln!(f, "free:   {}", self.free)?;
+        }
+        write!(f, "needed: {}", self.needed)
+    }
+}
+
+fn parse_arg<T: FromStr>(args: &[String], name: &str, default: T) -> io::Result<T> {
+    match args.iter().find_map(|arg| arg.strip_prefix(name)) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| io::Error::other(format!("Invalid value for {name}"))),
+        None => Ok(default),
     }
 }
 
+// Options that apply to every mode and may appear anywhere on the command line
+fn is_global_option(arg: &str) -> bool {
+    matches!(arg, "-q" | "-v" | "-vv" | "-vvv")
+        || arg.starts_with("--disk=")
+        || arg.starts_with("--required=")
+}
+
 fn main() -> io::Result<()> {
-    let root = Rc::new(RefCell::new(Dir {
-        name: String::from("/"),
-        contents: Rc::new(RefCell::new(Vec::new())),
-        size: 0,
-    }));
-    let mut state: State = State {
-        root: root.clone(),
-        current_path: vec![root],
-    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    Level::init(&args);
+    let disk_size = parse_arg(&args, "--disk=", DISK_SIZE)?;
+    let required_space = parse_arg(&args, "--required=", REQUIRED_SPACE)?;
+    // What is left starts with the subcommand, if any
+    let args: Vec<String> = args
+        .into_iter()
+        .filter(|arg| !is_global_option(arg))
+        .collect();
 
-    let contents = read_to_string("input.txt")?;
-    let mut lines = contents.lines();
-    while let Some(line) = lines.next() {
-        if line.starts_with('$') {
-            let op = line
-                .parse::<Operations>()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid operation"))?;
+    if args.first().map(String::as_str) == Some("transcript") {
+        let source = args
+            .get(1)
+            .ok_or_else(|| io::Error::other("Expected a directory to explore"))?;
+        print!("{}", transcript_from_dir(Path::new(source))?);
+        return Ok(());
+    }
 
-            match op {
-                Operations::CD(s) => {
-                    state
-                        .cd(&s)
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "CD operation failed"))?;
-                }
-                Operations::LS => {
-                    state
-                        .ls(&mut lines)
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "LS operation failed"))?;
-                }
+    if args.first().map(String::as_str) == Some("diff") {
+        let [before, after] = [args.get(1), args.get(2)].map(|path| {
+            let path = path.ok_or_else(|| io::Error::other("Expected two transcripts"))?;
+            let mut state = State::new();
+            state.run(&read_to_string(path)?)?;
+            Ok::<_, io::Error>(state.fs)
+        });
+        print_diff(&diff(&before?, &after?));
+        return Ok(());
+    }
+
+    let transcript = read_to_string("input.txt")?;
+    if args.first().map(String::as_str) == Some("check") {
+        let conflicts = State::check(&transcript)?;
+        for conflict in &conflicts {
+            println!("{conflict}");
+        }
+        if conflicts.is_empty() {
+            println!("no conflicts");
+        }
+        return Ok(());
+    }
+
+    let mut state = State::new();
+    state.run(&transcript)?;
+
+    let used = state.fs.size(FileSystem::ROOT);
+    match args.first().map(String::as_str) {
+        Some("tree") => {
+            print!(
+                "{}",
+                state.fs.render_tree(&RenderOptions::from_args(&args)?)
+            );
+            return Ok(());
+        }
+        Some("du") => {
+            print!("{}", state.fs.render_du(&RenderOptions::from_args(&args)?));
+            return Ok(());
+        }
+        Some("json") => {
+            println!("{}", state.fs.to_json(FileSystem::ROOT));
+            return Ok(());
+        }
+        Some("find") => {
+            print_entries(&state.fs, &Query::from_args(&args)?.find(&state.fs));
+            return Ok(());
+        }
+        Some("largest") => {
+            let n = args
+                .get(1)
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| io::Error::other("Expected how many entries to show"))?;
+            print_entries(&state.fs, &Query::from_args(&args)?.largest(&state.fs, n));
+            return Ok(());
+        }
+        Some("materialize") => {
+            let target = args
+                .get(1)
+                .ok_or_else(|| io::Error::other("Expected a target directory"))?;
+            return state.fs.materialize(Path::new(target));
+        }
+        _ => {}
+    }
+
+    let sizes = state.fs.dir_sizes();
+
+    let part1 = sizes
+        .iter()
+        .filter(|&&size| size <= SMALL_DIR_SIZE)
+        .try_fold(0u64, |total, &size| total.checked_add(size))
+        .ok_or_else(|| io::Error::other("Part 1 total overflows"))?;
+
+    println!("{part1}");
+
+    // Part 2 can fail on its own, the capacity report still tells why
+    let capacity = Capacity::new(disk_size, used, required_space);
+    let part2 = sizes
+        .iter()
+        .copied()
+        .filter(|&size| size >= capacity.needed)
+        .min();
+    if let Some(part2) = part2 {
+        println!("{part2}");
+    }
+    println!("{capacity}");
+
+    part2
+        .map(|_| ())
+        .ok_or_else(|| io::Error::other("No directory frees enough space"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(transcript: &str) -> FileSystem {
+        let mut state = State::new();
+        state.run(transcript).expect("transcript should replay");
+        state.fs
+    }
+
+    fn assert_sizes_match(fs: &FileSystem) {
+        for id in 0..fs.nodes.len() {
+            if let Content::Dir(_) = fs.nodes[id].content {
+                assert_eq!(
+                    fs.calculate_dir_size(id).ok(),
+                    Some(fs.size(id)),
+                    "cached size of {}",
+                    fs.path(id)
+                );
             }
         }
     }
 
-    println!("{}", state.calculate_total_size());
+    const SAMPLE: &str = "$ cd /
+$ ls
+dir a
+14848514 b.txt
+8504156 c.dat
+dir d
+$ cd a
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst
+$ cd e
+$ ls
+584 i
+$ cd ..
+$ cd ..
+$ cd d
+$ ls
+4060174 j
+8033020 d.log
+5626152 d.ext
+7214296 k
+";
 
-    Ok(())
+    #[test]
+    fn sample_sizes_match_recomputation() {
+        let fs = build(SAMPLE);
+        assert_sizes_match(&fs);
+        assert_eq!(fs.size(FileSystem::ROOT), 48381165);
+        assert_eq!(fs.size(fs.resolve(FileSystem::ROOT, "/a/e").unwrap()), 584);
+    }
+
+    #[test]
+    fn repeated_listings_are_not_counted_twice() {
+        let fs = build(&format!("{SAMPLE}$ cd /\n$ ls\ndir a\n14848514 b.txt\n$ ls a\n29116 f\ndir e\n$ ls a/e\n584 i\n"));
+        assert_sizes_match(&fs);
+        assert_eq!(fs.size(FileSystem::ROOT), 48381165);
+    }
+
+    #[test]
+    fn listings_through_paths_update_every_ancestor() {
+        let fs =
+            build("$ cd /\n$ ls\ndir a\n$ ls a\ndir b\n$ ls a/b\n3 z\n$ cd a/b\n$ ls\n3 z\n4 y\n");
+        assert_sizes_match(&fs);
+        assert_eq!(fs.size(FileSystem::ROOT), 7);
+    }
+
+    #[test]
+    fn overflowing_listing_is_rejected() {
+        let mut state = State::new();
+        assert!(state
+            .run("$ cd /\n$ ls\n18446744073709551615 a\n1 b\n")
+            .is_err());
+    }
+
+    #[test]
+    fn operations_parse() {
+        let parse = |line: &str| line.parse::<Operations>().ok();
+        assert_eq!(parse("$ pwd"), Some(Operations::Pwd));
+        assert_eq!(parse("$ ls"), Some(Operations::Ls(None)));
+        assert_eq!(
+            parse("$ ls a/b"),
+            Some(Operations::Ls(Some(String::from("a/b"))))
+        );
+        assert_eq!(
+            parse("$  cd   /a/b "),
+            Some(Operations::Cd(String::from("/a/b")))
+        );
+        assert_eq!(parse("$ cd .."), Some(Operations::Cd(String::from(".."))));
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        for line in [
+            "$ rm -rf /",
+            "$ cd",
+            "$ cd a b",
+            "$ ls a b",
+            "$ pwd -P",
+            "cd a",
+            "$",
+        ] {
+            assert!(line.parse::<Operations>().is_err(), "{line:?}");
+        }
+        assert!(State::new().run("$ cd /\n$ mkdir a\n").is_err());
+    }
+
+    const NESTED: &str = "$ cd /\n$ ls\ndir a\n$ ls a\ndir b\n$ ls a/b\ndir c\n";
+
+    #[test]
+    fn resolve_paths() {
+        let fs = build(NESTED);
+        let c = fs.resolve(FileSystem::ROOT, "a/b/c").unwrap();
+        assert_eq!(fs.path(c), "/a/b/c");
+        assert_eq!(fs.resolve(c, "/a/b"), fs.resolve(FileSystem::ROOT, "a/b"));
+        assert_eq!(fs.resolve(c, "/"), Some(FileSystem::ROOT));
+        assert_eq!(fs.resolve(c, "."), Some(c));
+        assert_eq!(fs.resolve(c, "./././"), Some(c));
+        assert_eq!(fs.path(fs.resolve(c, "..").unwrap()), "/a/b");
+        assert_eq!(fs.path(fs.resolve(c, "../../b/./c").unwrap()), "/a/b/c");
+        assert_eq!(fs.resolve(FileSystem::ROOT, ".."), Some(FileSystem::ROOT));
+        assert_eq!(fs.resolve(c, "/../../a"), fs.resolve(FileSystem::ROOT, "a"));
+        assert_eq!(fs.resolve(c, "d"), None);
+        assert_eq!(fs.resolve(FileSystem::ROOT, "a/x/c"), None);
+    }
+
+    #[test]
+    fn cd_follows_multi_segment_paths() {
+        let mut state = State::new();
+        state
+            .run(&format!(
+                "{NESTED}$ cd a/b/c\n$ pwd\n/a/b/c\n$ cd ../..\n$ pwd\n/a\n"
+            ))
+            .unwrap();
+        assert_eq!(state.path_string(), "/a");
+        state.run("$ cd /\n$ cd ..\n$ pwd\n/\n$ cd /a/b\n").unwrap();
+        assert_eq!(state.path_string(), "/a/b");
+        assert!(State::new().run("$ cd /\n$ cd missing\n").is_err());
+    }
+
+    #[test]
+    fn pwd_output_must_match() {
+        assert!(State::new().run("$ cd /\n$ pwd\n/\n").is_ok());
+        assert!(State::new()
+            .run(&format!("{NESTED}$ cd a\n$ pwd\n/a/b\n"))
+            .is_err());
+    }
+
+    #[test]
+    fn ls_with_a_path_lists_that_directory() {
+        let fs = build("$ cd /\n$ ls\ndir a\n$ ls a\n5 f\n$ ls /a\n5 f\n");
+        let a = fs.resolve(FileSystem::ROOT, "a").unwrap();
+        assert_eq!(fs.size(a), 5);
+        assert!(fs.child(FileSystem::ROOT, "f").is_none());
+        assert!(fs.child(a, "f").is_some());
+    }
+
+    fn conflicts(transcript: &str) -> Vec<String> {
+        State::check(transcript)
+            .expect("transcript should replay")
+            .iter()
+            .map(Conflict::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn consistent_transcript_has_no_conflicts() {
+        assert!(conflicts(SAMPLE).is_empty());
+    }
+
+    #[test]
+    fn size_changed() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\n5 a\n$ ls\n6 a\n"),
+            ["line 5: /a has size 6, but 5 on line 3"]
+        );
+    }
+
+    #[test]
+    fn kind_changed() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\n5 a\n$ ls\ndir a\n"),
+            ["line 5: /a is listed as both a file and a directory (line 3)"]
+        );
+    }
+
+    #[test]
+    fn disappeared() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\n5 a\n6 b\n$ ls\n5 a\n"),
+            ["line 5: /b is missing from this listing, but was listed on line 4"]
+        );
+    }
+
+    #[test]
+    fn unknown_dir_is_not_reported_as_disappeared() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\n5 a\n$ cd b\n$ ls\n1 c\n$ cd /\n$ ls\n5 a\n"),
+            ["line 4: /b was never listed by its parent"]
+        );
+        assert_eq!(
+            conflicts("$ cd /\n$ ls /b\n1 c\n$ ls\ndir b\n$ ls\n5 a\n"),
+            [
+                "line 2: /b was never listed by its parent",
+                "line 6: /b is missing from this listing, but was listed on line 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_pwd() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\ndir a\n$ cd a\n$ ls\n$ pwd\n/b\n"),
+            ["line 7: pwd printed /b, expected /a"]
+        );
+    }
+
+    #[test]
+    fn unlisted() {
+        assert_eq!(
+            conflicts("$ cd /\n$ ls\ndir a\n5 b\n"),
+            ["line 3: contents of /a are never listed"]
+        );
+    }
+
+    #[test]
+    fn diff_reports_changes_and_directory_deltas() {
+        let before = build("$ cd /\n$ ls\ndir a\n5 x\n3 gone\n$ cd a\n$ ls\n7 y\n2 same\n");
+        let after = build(
+            "$ cd /\n$ ls\ndir a\ndir x\n$ cd a\n$ ls\n9 y\n2 same\n4 new\n$ cd /x\n$ ls\n1 z\n",
+        );
+        let lines: Vec<(char, String, String)> = diff(&before, &after)
+            .iter()
+            .map(|line| (line.status(), line.path.clone(), line.delta()))
+            .collect();
+        let expected = [
+            ('M', "/", "-1"),
+            ('M', "/a/", "+6"),
+            ('A', "/a/new", "+4"),
+            ('M', "/a/y", "+2"),
+            ('D', "/gone", "-3"),
+            ('D', "/x", "-5"),
+            ('A', "/x/", "+1"),
+            ('A', "/x/z", "+1"),
+        ]
+        .map(|(status, path, delta)| (status, String::from(path), String::from(delta)));
+        assert_eq!(lines, expected);
+        assert!(diff(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn materialized_tree_transcribes_back() {
+        let target = env::temp_dir().join(format!("day07-round-trip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&target);
+        let original = build(SAMPLE);
+        original.materialize(&target).unwrap();
+        let transcript = transcript_from_dir(&target);
+        fs::remove_dir_all(&target).unwrap();
+
+        let replayed = build(&transcript.unwrap());
+        // Listings come back sorted by name, so compare the trees by path
+        assert!(diff(&original, &replayed).is_empty());
+        assert_eq!(replayed.nodes.len(), original.nodes.len());
+    }
 }